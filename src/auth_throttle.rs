@@ -0,0 +1,302 @@
+use std::{collections::VecDeque, hash::Hash, net::IpAddr};
+
+use dashmap::DashMap;
+use ipnet::{IpNet, Ipv4Net, Ipv6Net};
+use smol_str::SmolStr;
+
+use crate::config::AuthConfig;
+
+#[derive(Default)]
+struct Failures {
+    // consecutive failures since the counter was reset
+    count: u32,
+    last_failure: u64,
+    locked_until: u64,
+    // failure times inside the ban window, kept for IPs only
+    recent: VecDeque<u64>,
+}
+
+impl Failures {
+    fn is_stale(&self, now: u64, ttl: u64) -> bool {
+        self.locked_until <= now && now.saturating_sub(self.last_failure) >= ttl
+    }
+
+    fn retry_after(&self, now: u64) -> Option<u64> {
+        (self.locked_until > now).then(|| self.locked_until - now)
+    }
+
+    fn fail(&mut self, now: u64, free_attempts: u32, config: &AuthConfig) {
+        if self.is_stale(now, config.failure_ttl_secs) {
+            self.count = 0;
+        }
+        self.count = self.count.saturating_add(1);
+        self.last_failure = now;
+
+        if self.count > free_attempts {
+            let doublings = self.count - free_attempts - 1;
+            let lockout = config
+                .lockout_secs
+                .saturating_mul(2u64.saturating_pow(doublings))
+                .min(config.max_lockout_secs);
+            self.locked_until = now.saturating_add(lockout);
+        }
+    }
+}
+
+/// Failed login counters with exponential lockout, per login and per client network.
+///
+/// Counters don't care whether the login exists, so the lockout of an unknown
+/// login looks exactly like the lockout of a real one. Times are unix seconds.
+pub struct AuthThrottle {
+    config: AuthConfig,
+    logins: DashMap<SmolStr, Failures>,
+    // a single IPv4 address or an IPv6 network of `ipv6_prefix_len`
+    ips: DashMap<IpNet, Failures>,
+}
+
+/// Attempt counted as failed before the password is checked, so concurrent
+/// attempts can't slip past the lockout. Settled by `fail` or `succeed`.
+#[must_use]
+pub struct Attempt {
+    client: IpNet,
+    // lockout of the client set by this attempt and the one it replaced
+    client_lock: Option<(u64, u64)>,
+}
+
+impl AuthThrottle {
+    pub fn new(config: AuthConfig) -> AuthThrottle {
+        AuthThrottle {
+            config,
+            logins: DashMap::new(),
+            ips: DashMap::new(),
+        }
+    }
+
+    pub fn ip_ban_secs(&self) -> u64 {
+        self.config.ip_ban_secs
+    }
+
+    // one host can own a whole IPv6 network
+    fn client(&self, ip: IpAddr) -> IpNet {
+        match ip {
+            IpAddr::V4(ip) => Ipv4Net::from(ip).into(),
+            IpAddr::V6(ip) => Ipv6Net::new(ip, self.config.ipv6_prefix_len)
+                .unwrap()
+                .trunc()
+                .into(),
+        }
+    }
+
+    /// Counts the attempt as failed if it's allowed now,
+    /// otherwise returns seconds until the next attempt is allowed.
+    pub fn reserve(&self, login: &str, ip: IpAddr, now: u64) -> Result<Attempt, u64> {
+        let config = &self.config;
+        let client = self.client(ip);
+
+        // always taken in this order
+        let mut login_rec = self.logins.entry(login.into()).or_default();
+        let mut client_rec = self.ips.entry(client).or_default();
+        if let Some(retry_after) = login_rec.retry_after(now).max(client_rec.retry_after(now)) {
+            return Err(retry_after);
+        }
+
+        login_rec.fail(now, config.login_free_attempts, config);
+        let locked_until = client_rec.locked_until;
+        client_rec.fail(now, config.ip_free_attempts, config);
+        let client_lock = (client_rec.locked_until != locked_until)
+            .then_some((locked_until, client_rec.locked_until));
+
+        Ok(Attempt {
+            client,
+            client_lock,
+        })
+    }
+
+    /// The attempt did fail, returns the client network if it has to be banned.
+    pub fn fail(&self, attempt: Attempt, now: u64) -> Option<IpNet> {
+        let config = &self.config;
+        if config.ip_ban_failures == 0 {
+            return None;
+        }
+
+        let mut rec = self.ips.entry(attempt.client).or_default();
+        let window_start = now.saturating_sub(config.ip_ban_window_secs);
+        while rec.recent.front().is_some_and(|&at| at <= window_start) {
+            rec.recent.pop_front();
+        }
+        rec.recent.push_back(now);
+        if rec.recent.len() < config.ip_ban_failures as usize {
+            return None;
+        }
+
+        rec.recent.clear();
+        Some(attempt.client)
+    }
+
+    /// The login is trusted again, the attempt isn't held against the client.
+    /// Earlier failures of the client stay.
+    pub fn succeed(&self, login: &str, attempt: Attempt) {
+        self.logins.remove(login);
+
+        let Some(mut rec) = self.ips.get_mut(&attempt.client) else {
+            return;
+        };
+        rec.count = rec.count.saturating_sub(1);
+        if let Some((before, after)) = attempt.client_lock {
+            // unless a later failure prolonged it
+            if rec.locked_until == after {
+                rec.locked_until = before;
+            }
+        }
+    }
+
+    /// Drops counters which neither lock anything nor count anymore.
+    pub fn prune(&self, now: u64) {
+        prune(&self.logins, now, &self.config);
+        prune(&self.ips, now, &self.config);
+    }
+}
+
+fn prune<K: Eq + Hash>(map: &DashMap<K, Failures>, now: u64, config: &AuthConfig) {
+    let window_start = now.saturating_sub(config.ip_ban_window_secs);
+    map.retain(|_, rec| {
+        !rec.is_stale(now, config.failure_ttl_secs)
+            || rec.recent.back().is_some_and(|&at| at > window_start)
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::IpAddr;
+
+    use ipnet::IpNet;
+
+    use crate::{auth_throttle::AuthThrottle, config::AuthConfig};
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    // a failed attempt, returns the network to ban or seconds to wait
+    fn fail(
+        throttle: &AuthThrottle,
+        login: &str,
+        ip: IpAddr,
+        now: u64,
+    ) -> Result<Option<IpNet>, u64> {
+        let attempt = throttle.reserve(login, ip, now)?;
+        Ok(throttle.fail(attempt, now))
+    }
+
+    #[test]
+    fn test_lockout() {
+        let throttle = AuthThrottle::new(AuthConfig {
+            login_free_attempts: 2,
+            ip_free_attempts: 100,
+            lockout_secs: 10,
+            max_lockout_secs: 30,
+            ..AuthConfig::default()
+        });
+        let client = ip("1.2.3.4");
+        let fail = |login, now| fail(&throttle, login, client, now);
+
+        assert_eq!(Ok(None), fail("abcde", 1000));
+        assert_eq!(Ok(None), fail("abcde", 1000));
+
+        // every failure above the free ones doubles the lockout up to the cap
+        assert_eq!(Ok(None), fail("abcde", 1000));
+        assert_eq!(Err(10), fail("abcde", 1000));
+        assert_eq!(Ok(None), fail("abcde", 1010));
+        assert_eq!(Err(20), fail("abcde", 1010));
+        assert_eq!(Ok(None), fail("abcde", 1030));
+        assert_eq!(Err(30), fail("abcde", 1030));
+
+        // other logins from the same IP aren't affected
+        let attempt = throttle.reserve("fghij", client, 1030).unwrap();
+        throttle.succeed("fghij", attempt);
+
+        let attempt = throttle.reserve("abcde", client, 1060).unwrap();
+        throttle.succeed("abcde", attempt);
+        assert_eq!(Ok(None), fail("abcde", 1060));
+        assert_eq!(Ok(None), fail("abcde", 1060));
+    }
+
+    #[test]
+    fn test_reserve() {
+        let throttle = AuthThrottle::new(AuthConfig {
+            login_free_attempts: 1,
+            ip_free_attempts: 100,
+            lockout_secs: 10,
+            ..AuthConfig::default()
+        });
+        let client = ip("1.2.3.4");
+
+        // attempts in flight count before their passwords are checked
+        let first = throttle.reserve("abcde", client, 1000).unwrap();
+        let second = throttle.reserve("abcde", client, 1000).unwrap();
+        assert_eq!(Some(10), throttle.reserve("abcde", client, 1000).err());
+        assert_eq!(None, throttle.fail(first, 1000));
+        throttle.succeed("abcde", second);
+        assert!(throttle.reserve("abcde", client, 1000).is_ok());
+
+        // a success doesn't leave the client locked out
+        let throttle = AuthThrottle::new(AuthConfig {
+            login_free_attempts: 100,
+            ip_free_attempts: 1,
+            lockout_secs: 10,
+            ..AuthConfig::default()
+        });
+        let first = throttle.reserve("a", client, 1000).unwrap();
+        let second = throttle.reserve("b", client, 1000).unwrap();
+        assert_eq!(Some(10), throttle.reserve("c", client, 1000).err());
+        throttle.succeed("b", second);
+        let third = throttle.reserve("c", client, 1000).unwrap();
+        throttle.succeed("c", third);
+        assert_eq!(None, throttle.fail(first, 1000));
+    }
+
+    #[test]
+    fn test_ip() {
+        let throttle = AuthThrottle::new(AuthConfig {
+            login_free_attempts: 100,
+            ip_free_attempts: 3,
+            ip_ban_failures: 5,
+            ip_ban_window_secs: 60,
+            ..AuthConfig::default()
+        });
+        let client = ip("2a00::1");
+
+        // spraying different logins still locks the IP out
+        for (n, login) in ["a", "b", "c"].into_iter().enumerate() {
+            assert_eq!(Ok(None), fail(&throttle, login, client, 1000 + n as u64));
+        }
+        assert_eq!(Ok(None), fail(&throttle, "d", client, 1003));
+        assert_eq!(Err(1), fail(&throttle, "d", client, 1003));
+        // the whole /64 is one client
+        assert_eq!(Err(1), fail(&throttle, "d", ip("2a00::ffff:1"), 1003));
+        assert_eq!(Ok(None), fail(&throttle, "d", ip("2a00:0:0:1::1"), 1003));
+
+        // the fifth failure within the window bans the network
+        assert_eq!(
+            Ok(Some("2a00::/64".parse().unwrap())),
+            fail(&throttle, "e", client, 1004)
+        );
+        // earlier failures left the window
+        assert_eq!(Ok(None), fail(&throttle, "f", client, 1100));
+    }
+
+    #[test]
+    fn test_prune() {
+        let throttle = AuthThrottle::new(AuthConfig {
+            failure_ttl_secs: 100,
+            ..AuthConfig::default()
+        });
+        assert_eq!(Ok(None), fail(&throttle, "abcde", ip("1.2.3.4"), 1000));
+
+        throttle.prune(1099);
+        assert_eq!(1, throttle.logins.len());
+        throttle.prune(1100);
+        assert!(throttle.logins.is_empty());
+        assert!(throttle.ips.is_empty());
+    }
+}
@@ -0,0 +1,47 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize};
+use smol_str::SmolStr;
+
+/// Details of a user or subnet ban.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BanInfo {
+    // unix time in seconds, the ban is permanent without it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<SmolStr>,
+    // login of the admin who issued the ban
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub admin: Option<SmolStr>,
+}
+
+/// What a ban applies to, the key of expiry timers.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BanTarget {
+    User(SmolStr),
+    Subnet(IpNet),
+}
+
+#[derive(Debug, Serialize)]
+pub struct BannedUser {
+    pub login: SmolStr,
+    #[serde(flatten)]
+    pub ban: BanInfo,
+}
+
+#[derive(Debug, Serialize)]
+pub struct BannedSubnet {
+    pub subnet: IpNet,
+    #[serde(flatten)]
+    pub ban: BanInfo,
+}
+
+/// Seconds since the unix epoch.
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since| since.as_secs())
+        .unwrap_or(0)
+}
@@ -0,0 +1,184 @@
+use std::net::IpAddr;
+
+use ipnet::{IpNet, Ipv4Net, Ipv6Net};
+
+// prefix length of the IPv4-mapped range `::ffff:0:0/96`
+const MAPPED_PREFIX_LEN: u8 = 96;
+
+use crate::sharded_prefix_set::ShardedPrefixSet;
+
+/// Banned networks of both address families.
+///
+/// Every ban is a separate rule identified by its network, host bits are
+/// ignored. Rules may overlap: an address is banned while any rule covers it,
+/// and unban removes only the exactly matching rule. Lookups may run
+/// concurrently with anything, but `ban` and `unban` calls must be serialized.
+#[derive(Default)]
+pub struct SubnetBlacklist {
+    v4: ShardedPrefixSet<Ipv4Net>,
+    v6: ShardedPrefixSet<Ipv6Net>,
+}
+
+/// Truncates host bits and turns IPv4-mapped networks (`::ffff:a.b.c.d/n`)
+/// into IPv4 ones, which is what clients are matched against.
+pub fn canonical(subnet: IpNet) -> IpNet {
+    let subnet = subnet.trunc();
+    match subnet {
+        IpNet::V6(v6) if v6.prefix_len() >= MAPPED_PREFIX_LEN => match v6.addr().to_ipv4_mapped() {
+            Some(addr) => {
+                IpNet::V4(Ipv4Net::new(addr, v6.prefix_len() - MAPPED_PREFIX_LEN).unwrap())
+            }
+            None => subnet,
+        },
+        _ => subnet,
+    }
+}
+
+impl SubnetBlacklist {
+    /// Returns false if the same rule already exists.
+    pub fn ban(&self, subnet: IpNet) -> bool {
+        match canonical(subnet) {
+            IpNet::V4(subnet) => self.v4.insert(subnet),
+            IpNet::V6(subnet) => self.v6.insert(subnet),
+        }
+    }
+
+    /// Returns false if there is no such rule, overlapping rules aren't touched.
+    pub fn unban(&self, subnet: IpNet) -> bool {
+        match canonical(subnet) {
+            IpNet::V4(subnet) => self.v4.remove(subnet),
+            IpNet::V6(subnet) => self.v6.remove(subnet),
+        }
+    }
+
+    /// The most specific rule covering `ip`.
+    pub fn banned_by(&self, ip: IpAddr) -> Option<IpNet> {
+        match ip.to_canonical() {
+            IpAddr::V4(ip) => self.v4.longest_match(ip).map(IpNet::V4),
+            IpAddr::V6(ip) => self.v6.longest_match(ip).map(IpNet::V6),
+        }
+    }
+
+    pub fn rules(&self) -> Vec<IpNet> {
+        let v4 = self.v4.prefixes().into_iter().map(IpNet::V4);
+        let v6 = self.v6.prefixes().into_iter().map(IpNet::V6);
+        v4.chain(v6).collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::IpAddr;
+
+    use ipnet::IpNet;
+
+    use crate::blacklist::{canonical, SubnetBlacklist};
+
+    fn net(s: &str) -> IpNet {
+        s.parse().unwrap()
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_ban_unban() {
+        let list = SubnetBlacklist::default();
+        assert!(list.ban(net("10.0.0.0/8")));
+        assert!(!list.ban(net("10.0.0.0/8")));
+        // same rule, host bits don't matter
+        assert!(!list.ban(net("10.1.2.3/8")));
+
+        assert_eq!(Some(net("10.0.0.0/8")), list.banned_by(ip("10.255.0.1")));
+        assert_eq!(None, list.banned_by(ip("11.0.0.1")));
+
+        assert!(list.unban(net("10.1.2.3/8")));
+        assert!(!list.unban(net("10.0.0.0/8")));
+        assert_eq!(None, list.banned_by(ip("10.255.0.1")));
+        assert!(list.rules().is_empty());
+    }
+
+    #[test]
+    fn test_short_masks() {
+        let list = SubnetBlacklist::default();
+        assert!(list.ban(net("16.0.0.0/4")));
+        assert_eq!(Some(net("16.0.0.0/4")), list.banned_by(ip("31.1.1.1")));
+        assert_eq!(None, list.banned_by(ip("32.1.1.1")));
+
+        assert!(list.ban(net("0.0.0.0/0")));
+        assert_eq!(Some(net("0.0.0.0/0")), list.banned_by(ip("32.1.1.1")));
+        assert_eq!(Some(net("16.0.0.0/4")), list.banned_by(ip("31.1.1.1")));
+
+        assert!(list.unban(net("16.0.0.0/4")));
+        assert!(list.unban(net("0.0.0.0/0")));
+        assert_eq!(None, list.banned_by(ip("31.1.1.1")));
+    }
+
+    #[test]
+    fn test_overlapping() {
+        let list = SubnetBlacklist::default();
+        assert!(list.ban(net("10.0.0.0/8")));
+        assert!(list.ban(net("10.1.0.0/16")));
+        assert_eq!(Some(net("10.1.0.0/16")), list.banned_by(ip("10.1.0.1")));
+
+        // the outer rule still covers the inner network
+        assert!(list.unban(net("10.1.0.0/16")));
+        assert_eq!(Some(net("10.0.0.0/8")), list.banned_by(ip("10.1.0.1")));
+
+        // and the inner rule survives removal of the outer one
+        assert!(list.ban(net("10.1.0.0/16")));
+        assert!(list.unban(net("10.0.0.0/8")));
+        assert_eq!(Some(net("10.1.0.0/16")), list.banned_by(ip("10.1.0.1")));
+        assert_eq!(None, list.banned_by(ip("10.2.0.1")));
+
+        // no rule for a network covered by another one
+        assert!(!list.unban(net("10.1.2.0/24")));
+        assert_eq!(vec![net("10.1.0.0/16")], list.rules());
+    }
+
+    #[test]
+    fn test_ipv6() {
+        let list = SubnetBlacklist::default();
+        assert!(list.ban(net("2001:db8::/32")));
+        assert!(list.ban(net("2001:db8:1::/48")));
+        assert!(list.ban(net("10.0.0.0/8")));
+
+        assert_eq!(
+            Some(net("2001:db8:1::/48")),
+            list.banned_by(ip("2001:db8:1::1"))
+        );
+        assert_eq!(
+            Some(net("2001:db8::/32")),
+            list.banned_by(ip("2001:db8:2::1"))
+        );
+        // families don't mix
+        assert_eq!(None, list.banned_by(ip("::a00:1")));
+
+        assert!(list.unban(net("2001:db8::/32")));
+        assert_eq!(None, list.banned_by(ip("2001:db8:2::1")));
+        assert_eq!(
+            vec![net("10.0.0.0/8"), net("2001:db8:1::/48")],
+            list.rules()
+        );
+    }
+
+    #[test]
+    fn test_ipv4_mapped() {
+        assert_eq!(net("10.0.0.0/8"), canonical(net("::ffff:10.1.2.3/104")));
+        assert_eq!(net("10.1.2.3/32"), canonical(net("::ffff:10.1.2.3/128")));
+        assert_eq!(net("::/64"), canonical(net("::ffff:10.1.2.3/64")));
+
+        let list = SubnetBlacklist::default();
+        assert!(list.ban(net("::ffff:10.0.0.0/104")));
+        assert!(!list.ban(net("10.0.0.0/8")));
+        assert_eq!(Some(net("10.0.0.0/8")), list.banned_by(ip("10.1.1.1")));
+        assert_eq!(
+            Some(net("10.0.0.0/8")),
+            list.banned_by(ip("::ffff:10.1.1.1"))
+        );
+
+        assert!(list.unban(net("::ffff:10.0.0.0/104")));
+        assert!(list.rules().is_empty());
+    }
+}
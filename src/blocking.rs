@@ -0,0 +1,104 @@
+use std::{
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        mpsc::{self, Receiver, Sender, TryRecvError},
+        Arc, Mutex, OnceLock,
+    },
+    thread,
+    time::Duration,
+};
+
+use anyhow::anyhow;
+
+// pool threads can't wake a monoio task, so results are polled for
+const POLL_PERIOD: Duration = Duration::from_millis(1);
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// Fixed set of threads for CPU-heavy work, like password hashing, which
+/// would otherwise stall every connection of a worker.
+struct Pool {
+    jobs: Sender<Job>,
+}
+
+impl Pool {
+    fn new(threads: usize) -> Pool {
+        let (jobs, queue) = mpsc::channel::<Job>();
+        let queue = Arc::new(Mutex::new(queue));
+        for i in 0..threads {
+            let queue = queue.clone();
+            thread::Builder::new()
+                .name(format!("blocking-{i}"))
+                .spawn(move || work(&queue))
+                .expect("unable to spawn a blocking pool thread");
+        }
+
+        Pool { jobs }
+    }
+}
+
+fn work(queue: &Mutex<Receiver<Job>>) {
+    loop {
+        // the lock is released before the job runs
+        let job = queue.lock().unwrap().recv();
+        let Ok(job) = job else {
+            return;
+        };
+        // a panicking job drops its result sender, the pool thread stays
+        let _ = panic::catch_unwind(AssertUnwindSafe(job));
+    }
+}
+
+fn pool() -> &'static Pool {
+    static POOL: OnceLock<Pool> = OnceLock::new();
+    POOL.get_or_init(|| {
+        let threads = thread::available_parallelism().map_or(1, |n| n.get());
+        Pool::new(threads)
+    })
+}
+
+/// Runs `f` on the blocking pool and waits for it without blocking the worker.
+pub async fn run<F, T>(f: F) -> anyhow::Result<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let (result, done) = mpsc::channel();
+    let job = Box::new(move || {
+        let _ = result.send(f());
+    });
+    pool()
+        .jobs
+        .send(job)
+        .map_err(|_| anyhow!("blocking pool is gone"))?;
+
+    loop {
+        match done.try_recv() {
+            Ok(value) => return Ok(value),
+            Err(TryRecvError::Empty) => monoio::time::sleep(POLL_PERIOD).await,
+            Err(TryRecvError::Disconnected) => return Err(anyhow!("blocking job panicked")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::blocking::run;
+
+    #[monoio::test(timer_enabled = true)]
+    async fn test_run() {
+        let thread = std::thread::current().id();
+        let other = run(move || std::thread::current().id()).await.unwrap();
+        assert_ne!(thread, other);
+
+        let jobs: Vec<_> = (0..16u64).map(|i| run(move || i * i)).collect();
+        let mut sum = 0;
+        for job in jobs {
+            sum += job.await.unwrap();
+        }
+        assert_eq!((0..16u64).map(|i| i * i).sum::<u64>(), sum);
+
+        assert!(run(|| panic!("boom")).await.is_err());
+        assert_eq!(4, run(|| 2 + 2).await.unwrap());
+    }
+}
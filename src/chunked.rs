@@ -0,0 +1,167 @@
+// chunk size line with extensions, and all trailer fields together
+const MAX_LINE_LEN: usize = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChunkedError {
+    Invalid,
+    TooLarge,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Part {
+    Size,
+    Data(u64),
+    DataEnd,
+    Trailers(usize),
+}
+
+/// Decoder of a `Transfer-Encoding: chunked` body, fed as the body arrives.
+///
+/// Decoding is in place: framing is cut out of the buffer as soon as it's
+/// parsed, so the decoded body is a contiguous slice right after the headers
+/// and whatever follows the last chunk is left untouched.
+pub struct ChunkedDecoder {
+    part: Part,
+    // decoded bytes so far
+    len: usize,
+    max_size: usize,
+}
+
+impl ChunkedDecoder {
+    pub fn new(max_size: usize) -> ChunkedDecoder {
+        ChunkedDecoder {
+            part: Part::Size,
+            len: 0,
+            max_size,
+        }
+    }
+
+    /// Decodes the body starting at `buf[start]` as far as it has arrived.
+    /// Returns the body length once the last chunk and trailers are in,
+    /// the body is then `buf[start..start + len]`.
+    pub fn decode(
+        &mut self,
+        buf: &mut Vec<u8>,
+        start: usize,
+    ) -> Result<Option<usize>, ChunkedError> {
+        loop {
+            let cursor = start + self.len;
+            let pending = &buf[cursor..];
+
+            match self.part {
+                Part::Size => {
+                    let (consumed, size) = match httparse::parse_chunk_size(pending) {
+                        Ok(httparse::Status::Complete(parsed)) => parsed,
+                        Ok(httparse::Status::Partial) if pending.len() > MAX_LINE_LEN => {
+                            return Err(ChunkedError::Invalid);
+                        }
+                        Ok(httparse::Status::Partial) => return Ok(None),
+                        Err(_) => return Err(ChunkedError::Invalid),
+                    };
+                    if consumed > MAX_LINE_LEN {
+                        return Err(ChunkedError::Invalid);
+                    }
+                    if size > (self.max_size - self.len) as u64 {
+                        return Err(ChunkedError::TooLarge);
+                    }
+
+                    buf.drain(cursor..cursor + consumed);
+                    self.part = match size {
+                        0 => Part::Trailers(0),
+                        size => Part::Data(size),
+                    };
+                }
+                Part::Data(remaining) => {
+                    if pending.is_empty() {
+                        return Ok(None);
+                    }
+                    // the data is in place already, framing before it is gone
+                    let n = remaining.min(pending.len() as u64);
+                    self.len += n as usize;
+                    self.part = match remaining - n {
+                        0 => Part::DataEnd,
+                        remaining => Part::Data(remaining),
+                    };
+                }
+                Part::DataEnd => {
+                    if pending.len() < 2 {
+                        return Ok(None);
+                    }
+                    if &pending[..2] != b"\r\n" {
+                        return Err(ChunkedError::Invalid);
+                    }
+                    buf.drain(cursor..cursor + 2);
+                    self.part = Part::Size;
+                }
+                // trailer fields aren't used, they are only skipped
+                Part::Trailers(seen) => {
+                    let Some(eol) = pending.windows(2).position(|w| w == b"\r\n") else {
+                        if seen + pending.len() > MAX_LINE_LEN {
+                            return Err(ChunkedError::Invalid);
+                        }
+                        return Ok(None);
+                    };
+                    if seen + eol > MAX_LINE_LEN {
+                        return Err(ChunkedError::Invalid);
+                    }
+
+                    buf.drain(cursor..cursor + eol + 2);
+                    if eol == 0 {
+                        return Ok(Some(self.len));
+                    }
+                    self.part = Part::Trailers(seen + eol);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::chunked::{ChunkedDecoder, ChunkedError};
+
+    fn decode_all(input: &[u8], max_size: usize) -> Result<Option<usize>, ChunkedError> {
+        let mut buf = input.to_vec();
+        ChunkedDecoder::new(max_size).decode(&mut buf, 0)
+    }
+
+    #[test]
+    fn test_decode() {
+        let mut buf = b"HEAD4\r\nWiki\r\n6;ext=1\r\npedia \r\n0\r\nX-Sum: 1\r\n\r\nNEXT".to_vec();
+        let mut decoder = ChunkedDecoder::new(100);
+
+        assert_eq!(Ok(Some(10)), decoder.decode(&mut buf, 4));
+        assert_eq!(b"HEADWikipedia NEXT", buf.as_slice());
+    }
+
+    #[test]
+    fn test_partial() {
+        let input = b"5\r\nhello\r\n3\r\nabc\r\n0\r\n\r\n";
+        let mut decoder = ChunkedDecoder::new(100);
+        let mut buf = Vec::new();
+
+        // byte by byte, as if every read returns a single byte
+        for (n, &byte) in input.iter().enumerate() {
+            buf.push(byte);
+            let decoded = decoder.decode(&mut buf, 0).unwrap();
+            assert_eq!(n == input.len() - 1, decoded.is_some());
+        }
+        assert_eq!(b"helloabc", buf.as_slice());
+    }
+
+    #[test]
+    fn test_invalid() {
+        assert_eq!(Err(ChunkedError::Invalid), decode_all(b"zz\r\n", 100));
+        assert_eq!(
+            Err(ChunkedError::Invalid),
+            decode_all(b"3\r\nabcde\r\n", 100)
+        );
+        assert_eq!(Err(ChunkedError::Invalid), decode_all(&[b'1'; 2000], 100));
+        assert_eq!(Err(ChunkedError::TooLarge), decode_all(b"40\r\n", 10));
+        assert_eq!(
+            Err(ChunkedError::TooLarge),
+            decode_all(b"8\r\n12345678\r\n8\r\n", 10)
+        );
+        assert_eq!(Ok(None), decode_all(b"8\r\n1234", 10));
+    }
+}
@@ -0,0 +1,653 @@
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+
+use anyhow::{bail, Context};
+use clap::Parser;
+use ipnet::IpNet;
+use serde::{Deserialize, Deserializer};
+
+use crate::{
+    forwarded::ForwardedHeader,
+    keys::Algorithm,
+    request::Route,
+    state::{NoncePolicy, TokenSettings},
+};
+
+const DEFAULT_USERS: &str = "/storage/data/users.jsonl";
+const DEFAULT_GEO_LOCATIONS: &str =
+    "/storage/data/GeoLite2-City-CSV/GeoLite2-City-Locations-en.csv";
+const DEFAULT_GEO_BLOCKS_IPV4: &str =
+    "/storage/data/GeoLite2-City-CSV/GeoLite2-City-Blocks-IPv4.csv";
+const DEFAULT_GEO_BLOCKS_IPV6: &str =
+    "/storage/data/GeoLite2-City-CSV/GeoLite2-City-Blocks-IPv6.csv";
+// one request in about 30 years, token intervals are kept in nanoseconds
+const MIN_RATE: f64 = 1e-9;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Driver {
+    // io_uring if the kernel supports it, epoll otherwise
+    Fusion,
+    IoUring,
+}
+
+impl FromStr for Driver {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "fusion" => Ok(Driver::Fusion),
+            "io_uring" => Ok(Driver::IoUring),
+            _ => bail!("unknown driver {s:?}, expected fusion or io_uring"),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub listen: Vec<SocketAddr>,
+    // 0 means the number of cores
+    pub workers: usize,
+    #[serde(deserialize_with = "from_str")]
+    pub driver: Driver,
+    pub shutdown_timeout_secs: u64,
+    // peers allowed to forward the client address, nobody by default
+    pub trusted_proxies: Vec<IpNet>,
+    #[serde(deserialize_with = "from_str")]
+    pub forwarded_header: ForwardedHeader,
+    // connections served at once by one worker, the rest are refused; 0 for no limit
+    pub max_connections_per_worker: usize,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            listen: vec![SocketAddr::from(([0, 0, 0, 0], 8080))],
+            workers: 0,
+            driver: Driver::Fusion,
+            shutdown_timeout_secs: 10,
+            trusted_proxies: Vec::new(),
+            forwarded_header: ForwardedHeader::XForwardedFor,
+            max_connections_per_worker: 10_000,
+        }
+    }
+}
+
+/// Limits of the http parser and of a connection.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Limits {
+    pub max_header_size: usize,
+    pub max_body_size: usize,
+    // an idle keep-alive connection is closed after that long
+    pub keep_alive_timeout_secs: u64,
+    // from the first byte of a request to the end of its headers
+    pub header_timeout_secs: u64,
+    // from the end of the headers to the end of the body
+    pub body_timeout_secs: u64,
+    // a client has to take the answers written so far within that long
+    pub write_timeout_secs: u64,
+    // requests served over one connection, 0 for no limit
+    pub max_requests_per_connection: u64,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_header_size: 10 * 1024,
+            max_body_size: 100 * 1024,
+            keep_alive_timeout_secs: 60,
+            header_timeout_secs: 10,
+            body_timeout_secs: 30,
+            write_timeout_secs: 10,
+            max_requests_per_connection: 10_000,
+        }
+    }
+}
+
+impl Limits {
+    pub fn keep_alive_timeout(&self) -> Duration {
+        Duration::from_secs(self.keep_alive_timeout_secs)
+    }
+
+    pub fn header_timeout(&self) -> Duration {
+        Duration::from_secs(self.header_timeout_secs)
+    }
+
+    pub fn body_timeout(&self) -> Duration {
+        Duration::from_secs(self.body_timeout_secs)
+    }
+
+    pub fn write_timeout(&self) -> Duration {
+        Duration::from_secs(self.write_timeout_secs)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DataConfig {
+    pub users: PathBuf,
+    pub geo_locations: PathBuf,
+    pub geo_blocks_ipv4: PathBuf,
+    // IPv6 clients fail country checks if the file is missing
+    pub geo_blocks_ipv6: PathBuf,
+    // persistence is enabled only with a state dir
+    pub state_dir: Option<PathBuf>,
+    pub snapshot_secs: u64,
+}
+
+impl Default for DataConfig {
+    fn default() -> Self {
+        DataConfig {
+            users: DEFAULT_USERS.into(),
+            geo_locations: DEFAULT_GEO_LOCATIONS.into(),
+            geo_blocks_ipv4: DEFAULT_GEO_BLOCKS_IPV4.into(),
+            geo_blocks_ipv6: DEFAULT_GEO_BLOCKS_IPV6.into(),
+            state_dir: None,
+            snapshot_secs: 5 * 60,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct JwtConfig {
+    #[serde(deserialize_with = "from_str")]
+    pub alg: Algorithm,
+    // base64 for HS256, PEM private key otherwise; takes precedence over key_file
+    pub key: Option<String>,
+    pub key_file: Option<PathBuf>,
+    // sign with a random key when none is configured, tokens don't survive restart
+    pub ephemeral_key: bool,
+    pub token_ttl_secs: u64,
+    // session lifetime, refresh doesn't extend it
+    pub refresh_ttl_secs: u64,
+    pub clock_skew_secs: u64,
+    #[serde(deserialize_with = "from_str")]
+    pub nonce_policy: NoncePolicy,
+}
+
+impl Default for JwtConfig {
+    fn default() -> Self {
+        JwtConfig {
+            alg: Algorithm::HS256,
+            key: None,
+            key_file: None,
+            ephemeral_key: false,
+            token_ttl_secs: 15 * 60,
+            refresh_ttl_secs: 30 * 24 * 60 * 60,
+            clock_skew_secs: 60,
+            nonce_policy: NoncePolicy::Any,
+        }
+    }
+}
+
+/// Brute-force protection of `/auth`.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    // failures of one login allowed before it's locked out
+    pub login_free_attempts: u32,
+    // failures from one IP allowed before it's locked out, across all logins
+    pub ip_free_attempts: u32,
+    // the first lockout, every further failure doubles it
+    pub lockout_secs: u64,
+    pub max_lockout_secs: u64,
+    // failures are forgotten after a quiet period
+    pub failure_ttl_secs: u64,
+    // IP is banned after that many failures within the window, 0 disables bans
+    pub ip_ban_failures: u32,
+    pub ip_ban_window_secs: u64,
+    pub ip_ban_secs: u64,
+    // IPv6 clients are counted and banned by networks of this length
+    pub ipv6_prefix_len: u8,
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        AuthConfig {
+            login_free_attempts: 5,
+            ip_free_attempts: 20,
+            lockout_secs: 1,
+            max_lockout_secs: 15 * 60,
+            failure_ttl_secs: 60 * 60,
+            ip_ban_failures: 0,
+            ip_ban_window_secs: 10 * 60,
+            ip_ban_secs: 60 * 60,
+            ipv6_prefix_len: 64,
+        }
+    }
+}
+
+/// Token bucket of `burst` requests refilled at `rate` requests per second.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RateLimit {
+    pub rate: f64,
+    pub burst: u32,
+}
+
+/// Request limits per client IP and per authenticated login.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimitConfig {
+    // routes without their own limit, unlimited if not set
+    pub default: Option<RateLimit>,
+    pub routes: HashMap<Route, RateLimit>,
+}
+
+/// Server configuration: defaults, overridden by the TOML file, overridden by CLI flags
+/// and their environment variables.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub limits: Limits,
+    pub data: DataConfig,
+    pub jwt: JwtConfig,
+    pub auth: AuthConfig,
+    pub rate_limits: RateLimitConfig,
+}
+
+#[derive(Debug, Parser)]
+#[command(about = "highload.fun auth server")]
+pub struct Cli {
+    /// TOML config file
+    #[arg(short, long, env = "HLFUN_CONFIG")]
+    config: Option<PathBuf>,
+
+    /// Address to listen on, may be repeated
+    #[arg(long, env = "HLFUN_LISTEN", value_delimiter = ',')]
+    listen: Vec<SocketAddr>,
+    /// Number of worker threads, 0 for the number of cores
+    #[arg(long, env = "HLFUN_WORKERS")]
+    workers: Option<usize>,
+    /// IO driver: fusion or io_uring
+    #[arg(long, env = "HLFUN_DRIVER")]
+    driver: Option<Driver>,
+    #[arg(long, env = "HLFUN_SHUTDOWN_TIMEOUT_SECS")]
+    shutdown_timeout_secs: Option<u64>,
+    /// Networks of proxies allowed to forward the client address
+    #[arg(long, env = "HLFUN_TRUSTED_PROXIES", value_delimiter = ',')]
+    trusted_proxies: Vec<IpNet>,
+    /// Header the proxies put the client address into: x-forwarded-for or forwarded
+    #[arg(long, env = "HLFUN_FORWARDED_HEADER")]
+    forwarded_header: Option<ForwardedHeader>,
+    /// Connections served at once by one worker, 0 for no limit
+    #[arg(long, env = "HLFUN_MAX_CONNECTIONS_PER_WORKER")]
+    max_connections_per_worker: Option<usize>,
+
+    #[arg(long, env = "HLFUN_MAX_HEADER_SIZE")]
+    max_header_size: Option<usize>,
+    #[arg(long, env = "HLFUN_MAX_BODY_SIZE")]
+    max_body_size: Option<usize>,
+    #[arg(long, env = "HLFUN_KEEP_ALIVE_TIMEOUT_SECS")]
+    keep_alive_timeout_secs: Option<u64>,
+    #[arg(long, env = "HLFUN_HEADER_TIMEOUT_SECS")]
+    header_timeout_secs: Option<u64>,
+    #[arg(long, env = "HLFUN_BODY_TIMEOUT_SECS")]
+    body_timeout_secs: Option<u64>,
+    #[arg(long, env = "HLFUN_WRITE_TIMEOUT_SECS")]
+    write_timeout_secs: Option<u64>,
+    /// Requests served over one connection, 0 for no limit
+    #[arg(long, env = "HLFUN_MAX_REQUESTS_PER_CONNECTION")]
+    max_requests_per_connection: Option<u64>,
+
+    /// Initial users, one JSON object per line
+    #[arg(long, env = "HLFUN_USERS")]
+    users: Option<PathBuf>,
+    /// GeoLite2 City locations CSV
+    #[arg(long, env = "HLFUN_GEO_LOCATIONS")]
+    geo_locations: Option<PathBuf>,
+    /// GeoLite2 City IPv4 blocks CSV
+    #[arg(long, env = "HLFUN_GEO_BLOCKS_IPV4")]
+    geo_blocks_ipv4: Option<PathBuf>,
+    /// GeoLite2 City IPv6 blocks CSV
+    #[arg(long, env = "HLFUN_GEO_BLOCKS_IPV6")]
+    geo_blocks_ipv6: Option<PathBuf>,
+    /// Directory for the journal and snapshots, enables persistence
+    #[arg(long, env = "HLFUN_STATE_DIR")]
+    state_dir: Option<PathBuf>,
+    #[arg(long, env = "HLFUN_SNAPSHOT_SECS")]
+    snapshot_secs: Option<u64>,
+
+    /// JWT algorithm: HS256, RS256, ES256 or EdDSA
+    #[arg(long, env = "HLFUN_JWT_ALG")]
+    jwt_alg: Option<Algorithm>,
+    /// Signing key itself, prefer --jwt-key-file
+    #[arg(long, env = "HLFUN_JWT_KEY", hide_env_values = true)]
+    jwt_key: Option<String>,
+    #[arg(long, env = "HLFUN_JWT_KEY_FILE")]
+    jwt_key_file: Option<PathBuf>,
+    /// Sign with a random key if none is configured, for tests and single-process setups
+    #[arg(long, env = "HLFUN_JWT_EPHEMERAL_KEY")]
+    jwt_ephemeral_key: bool,
+    #[arg(long, env = "HLFUN_TOKEN_TTL_SECS")]
+    token_ttl_secs: Option<u64>,
+    #[arg(long, env = "HLFUN_REFRESH_TTL_SECS")]
+    refresh_ttl_secs: Option<u64>,
+    #[arg(long, env = "HLFUN_CLOCK_SKEW_SECS")]
+    clock_skew_secs: Option<u64>,
+    /// any, latest or last:N
+    #[arg(long, env = "HLFUN_NONCE_POLICY")]
+    nonce_policy: Option<NoncePolicy>,
+}
+
+impl Config {
+    /// Builds the configuration from the command line, exits on `--help` or bad flags.
+    pub fn load() -> anyhow::Result<Config> {
+        Config::from_cli(Cli::parse())
+    }
+
+    pub fn from_cli(cli: Cli) -> anyhow::Result<Config> {
+        let mut config = match &cli.config {
+            Some(path) => Config::read(path)?,
+            None => Config::default(),
+        };
+
+        config.apply(cli);
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    pub fn read(path: &Path) -> anyhow::Result<Config> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("unable to read config {}", path.display()))?;
+        toml::from_str(&text).with_context(|| format!("invalid config {}", path.display()))
+    }
+
+    fn apply(&mut self, cli: Cli) {
+        fn set<T>(dst: &mut T, src: Option<T>) {
+            if let Some(src) = src {
+                *dst = src;
+            }
+        }
+
+        if !cli.listen.is_empty() {
+            self.server.listen = cli.listen;
+        }
+        set(&mut self.server.workers, cli.workers);
+        set(&mut self.server.driver, cli.driver);
+        set(
+            &mut self.server.shutdown_timeout_secs,
+            cli.shutdown_timeout_secs,
+        );
+        if !cli.trusted_proxies.is_empty() {
+            self.server.trusted_proxies = cli.trusted_proxies;
+        }
+        set(&mut self.server.forwarded_header, cli.forwarded_header);
+        set(
+            &mut self.server.max_connections_per_worker,
+            cli.max_connections_per_worker,
+        );
+
+        set(&mut self.limits.max_header_size, cli.max_header_size);
+        set(&mut self.limits.max_body_size, cli.max_body_size);
+        set(
+            &mut self.limits.keep_alive_timeout_secs,
+            cli.keep_alive_timeout_secs,
+        );
+        set(
+            &mut self.limits.header_timeout_secs,
+            cli.header_timeout_secs,
+        );
+        set(&mut self.limits.body_timeout_secs, cli.body_timeout_secs);
+        set(&mut self.limits.write_timeout_secs, cli.write_timeout_secs);
+        set(
+            &mut self.limits.max_requests_per_connection,
+            cli.max_requests_per_connection,
+        );
+
+        set(&mut self.data.users, cli.users);
+        set(&mut self.data.geo_locations, cli.geo_locations);
+        set(&mut self.data.geo_blocks_ipv4, cli.geo_blocks_ipv4);
+        set(&mut self.data.geo_blocks_ipv6, cli.geo_blocks_ipv6);
+        if cli.state_dir.is_some() {
+            self.data.state_dir = cli.state_dir;
+        }
+        set(&mut self.data.snapshot_secs, cli.snapshot_secs);
+
+        set(&mut self.jwt.alg, cli.jwt_alg);
+        if cli.jwt_key.is_some() {
+            self.jwt.key = cli.jwt_key;
+        }
+        if cli.jwt_key_file.is_some() {
+            self.jwt.key_file = cli.jwt_key_file;
+        }
+        if cli.jwt_ephemeral_key {
+            self.jwt.ephemeral_key = true;
+        }
+        set(&mut self.jwt.token_ttl_secs, cli.token_ttl_secs);
+        set(&mut self.jwt.refresh_ttl_secs, cli.refresh_ttl_secs);
+        set(&mut self.jwt.clock_skew_secs, cli.clock_skew_secs);
+        set(&mut self.jwt.nonce_policy, cli.nonce_policy);
+    }
+
+    fn validate(&self) -> anyhow::Result<()> {
+        if self.server.listen.is_empty() {
+            bail!("server.listen: at least one address is required");
+        }
+        if self.limits.max_header_size == 0 {
+            bail!("limits.max_header_size must be positive");
+        }
+        if self.limits.max_body_size == 0 {
+            bail!("limits.max_body_size must be positive");
+        }
+        if self.limits.keep_alive_timeout_secs == 0 {
+            bail!("limits.keep_alive_timeout_secs must be positive");
+        }
+        if self.limits.header_timeout_secs == 0 {
+            bail!("limits.header_timeout_secs must be positive");
+        }
+        if self.limits.body_timeout_secs == 0 {
+            bail!("limits.body_timeout_secs must be positive");
+        }
+        if self.limits.write_timeout_secs == 0 {
+            bail!("limits.write_timeout_secs must be positive");
+        }
+        if self.data.snapshot_secs == 0 {
+            bail!("data.snapshot_secs must be positive");
+        }
+        if self.jwt.token_ttl_secs == 0 {
+            bail!("jwt.token_ttl_secs must be positive");
+        }
+        if self.jwt.refresh_ttl_secs == 0 {
+            bail!("jwt.refresh_ttl_secs must be positive");
+        }
+        if self.auth.lockout_secs == 0 {
+            bail!("auth.lockout_secs must be positive");
+        }
+        if self.auth.max_lockout_secs < self.auth.lockout_secs {
+            bail!("auth.max_lockout_secs must not be less than auth.lockout_secs");
+        }
+        if self.auth.ip_ban_failures > 0 && self.auth.ip_ban_window_secs == 0 {
+            bail!("auth.ip_ban_window_secs must be positive when IP bans are enabled");
+        }
+        if !(1..=128).contains(&self.auth.ipv6_prefix_len) {
+            bail!("auth.ipv6_prefix_len must be between 1 and 128");
+        }
+        let limits = self
+            .rate_limits
+            .default
+            .iter()
+            .chain(self.rate_limits.routes.values());
+        for limit in limits {
+            if !(limit.rate.is_finite() && limit.rate > 0.0) || limit.burst == 0 {
+                bail!("rate_limits: rate and burst must be positive, got {limit:?}");
+            }
+            if limit.rate < MIN_RATE {
+                bail!("rate_limits: rate must be at least {MIN_RATE}, got {limit:?}");
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn workers(&self) -> usize {
+        match self.server.workers {
+            0 => std::thread::available_parallelism().map_or(1, |n| n.get()),
+            n => n,
+        }
+    }
+
+    pub fn shutdown_timeout(&self) -> Duration {
+        Duration::from_secs(self.server.shutdown_timeout_secs)
+    }
+
+    pub fn snapshot_period(&self) -> Duration {
+        Duration::from_secs(self.data.snapshot_secs)
+    }
+
+    pub fn token_settings(&self) -> TokenSettings {
+        use jwt_simple::prelude::Duration;
+
+        TokenSettings {
+            ttl: Duration::from_secs(self.jwt.token_ttl_secs),
+            refresh_ttl: Duration::from_secs(self.jwt.refresh_ttl_secs),
+            clock_skew: Duration::from_secs(self.jwt.clock_skew_secs),
+            nonce_policy: self.jwt.nonce_policy,
+        }
+    }
+}
+
+// enums parsed the same way in the file and on the command line
+fn from_str<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: Display,
+{
+    let s = String::deserialize(deserializer)?;
+    s.parse().map_err(serde::de::Error::custom)
+}
+
+#[cfg(test)]
+mod test {
+    use std::{net::SocketAddr, time::Duration};
+
+    use clap::Parser;
+
+    use crate::{
+        config::{Cli, Config, Driver, RateLimit},
+        forwarded::ForwardedHeader,
+        keys::Algorithm,
+        request::Route,
+        state::NoncePolicy,
+    };
+
+    #[test]
+    fn test_config() {
+        let mut config: Config = toml::from_str(
+            r#"
+            [server]
+            listen = ["127.0.0.1:8080", "[::1]:8080"]
+            driver = "io_uring"
+
+            [limits]
+            max_body_size = 2048
+            keep_alive_timeout_secs = 5
+
+            [jwt]
+            alg = "EdDSA"
+            nonce_policy = "last:3"
+
+            [auth]
+            ip_ban_failures = 50
+
+            [rate_limits]
+            default = { rate = 10, burst = 20 }
+            routes.auth = { rate = 0.5, burst = 3 }
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(2, config.server.listen.len());
+        assert_eq!(Driver::IoUring, config.server.driver);
+        assert_eq!(2048, config.limits.max_body_size);
+        assert_eq!(10 * 1024, config.limits.max_header_size);
+        assert_eq!(Duration::from_secs(5), config.limits.keep_alive_timeout());
+        assert_eq!(10_000, config.limits.max_requests_per_connection);
+        assert_eq!(Algorithm::EdDSA, config.jwt.alg);
+        assert_eq!(NoncePolicy::LastN(3), config.jwt.nonce_policy);
+        assert_eq!(50, config.auth.ip_ban_failures);
+        assert_eq!(5, config.auth.login_free_attempts);
+        assert_eq!(3, config.rate_limits.routes[&Route::Auth].burst);
+        assert_eq!(10.0, config.rate_limits.default.unwrap().rate);
+        // nobody forwards client addresses unless told so
+        assert!(config.server.trusted_proxies.is_empty());
+        assert_eq!(
+            ForwardedHeader::XForwardedFor,
+            config.server.forwarded_header
+        );
+
+        // flags win over the file
+        let cli = Cli::try_parse_from([
+            "hlfun_srv",
+            "--listen",
+            "0.0.0.0:9000",
+            "--workers",
+            "3",
+            "--nonce-policy",
+            "latest",
+            "--trusted-proxies",
+            "10.0.0.0/8,fd00::/8",
+            "--forwarded-header",
+            "forwarded",
+        ])
+        .unwrap();
+        config.apply(cli);
+        assert_eq!(
+            vec!["0.0.0.0:9000".parse::<SocketAddr>().unwrap()],
+            config.server.listen
+        );
+        assert_eq!(3, config.workers());
+        assert_eq!(NoncePolicy::LastN(1), config.jwt.nonce_policy);
+        assert_eq!(2, config.server.trusted_proxies.len());
+        assert_eq!(ForwardedHeader::Forwarded, config.server.forwarded_header);
+        assert!(config.validate().is_ok());
+
+        assert!(toml::from_str::<Config>("[server]\ndriver = \"epoll\"").is_err());
+        assert!(toml::from_str::<Config>("[server]\nport = 8080").is_err());
+        let unknown_route = "[rate_limits.routes]\nlogin = { rate = 1, burst = 1 }";
+        assert!(toml::from_str::<Config>(unknown_route).is_err());
+        assert!(Cli::try_parse_from(["hlfun_srv", "--workers", "many"]).is_err());
+
+        config
+            .rate_limits
+            .routes
+            .get_mut(&Route::Auth)
+            .unwrap()
+            .burst = 0;
+        assert!(config.validate().is_err());
+        config.rate_limits.routes.insert(
+            Route::Auth,
+            RateLimit {
+                rate: 1e-10,
+                burst: 1,
+            },
+        );
+        assert!(config.validate().is_err());
+        config.rate_limits.routes.clear();
+
+        config.limits.max_body_size = 0;
+        assert!(config.validate().is_err());
+        config.limits.max_body_size = 2048;
+
+        config.limits.header_timeout_secs = 0;
+        assert!(config.validate().is_err());
+        config.limits.header_timeout_secs = 10;
+
+        config.limits.write_timeout_secs = 0;
+        assert!(config.validate().is_err());
+
+        let example: Config = toml::from_str(include_str!("../config.example.toml")).unwrap();
+        assert!(example.validate().is_ok());
+    }
+}
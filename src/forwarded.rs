@@ -0,0 +1,217 @@
+use std::{
+    net::{IpAddr, Ipv6Addr, SocketAddr},
+    str::FromStr,
+};
+
+use anyhow::bail;
+use ipnet::IpNet;
+
+/// Header the proxies put the client address into.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ForwardedHeader {
+    XForwardedFor,
+    // RFC 7239
+    Forwarded,
+}
+
+impl FromStr for ForwardedHeader {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "x-forwarded-for" => Ok(ForwardedHeader::XForwardedFor),
+            "forwarded" => Ok(ForwardedHeader::Forwarded),
+            _ => bail!("unknown forwarded header {s:?}, expected x-forwarded-for or forwarded"),
+        }
+    }
+}
+
+/// Proxies allowed to tell the address of the client they forward.
+///
+/// Only the configured header is read: a proxy appends to the header it
+/// knows and passes the other one from the client as is.
+pub struct TrustedProxies {
+    nets: Vec<IpNet>,
+    header: ForwardedHeader,
+}
+
+impl TrustedProxies {
+    pub fn new(nets: Vec<IpNet>, header: ForwardedHeader) -> TrustedProxies {
+        TrustedProxies { nets, header }
+    }
+
+    fn is_trusted(&self, ip: IpAddr) -> bool {
+        self.nets.iter().any(|net| net.contains(&ip))
+    }
+
+    /// Whether the header with lowercase `name` carries the forwarding chain.
+    pub fn reads(&self, name: &str) -> bool {
+        match self.header {
+            ForwardedHeader::XForwardedFor => name == "x-forwarded-for",
+            ForwardedHeader::Forwarded => name == "forwarded",
+        }
+    }
+
+    /// Client address according to the forwarding `chain`, the values of
+    /// all the headers `reads` accepts joined with commas.
+    pub fn client_ip(&self, peer: IpAddr, chain: &str) -> Option<IpAddr> {
+        match self.header {
+            ForwardedHeader::XForwardedFor => self.resolve(peer, x_forwarded_for(chain)),
+            ForwardedHeader::Forwarded => self.resolve(peer, forwarded_for(chain)),
+        }
+    }
+
+    /// Walks `hops` from the nearest one while the address seen so far is
+    /// a trusted proxy, starting from the socket peer.
+    /// Returns `None` if a hop it had to look at is malformed.
+    fn resolve<'a>(
+        &self,
+        peer: IpAddr,
+        hops: impl DoubleEndedIterator<Item = &'a str>,
+    ) -> Option<IpAddr> {
+        // IPv4 clients of a dual-stack socket come as ::ffff:a.b.c.d
+        let mut client = peer.to_canonical();
+        for hop in hops.rev() {
+            if !self.is_trusted(client) {
+                break;
+            }
+            client = parse_node(hop)?.to_canonical();
+        }
+
+        Some(client)
+    }
+}
+
+/// Hops of `X-Forwarded-For`, the client first.
+fn x_forwarded_for(value: &str) -> impl DoubleEndedIterator<Item = &str> {
+    value.split_terminator(',').map(str::trim)
+}
+
+/// `for` nodes of the RFC 7239 `Forwarded` header, the client first.
+/// A hop without `for` yields an empty node.
+fn forwarded_for(value: &str) -> impl DoubleEndedIterator<Item = &str> {
+    value.split_terminator(',').map(|element| {
+        element
+            .split(';')
+            .filter_map(|pair| pair.split_once('='))
+            .find(|(name, _)| name.trim().eq_ignore_ascii_case("for"))
+            .map_or("", |(_, node)| node.trim())
+    })
+}
+
+// "1.2.3.4", "1.2.3.4:80", "2001:db8::1", "[2001:db8::1]:80", optionally quoted;
+// "unknown" and obfuscated identifiers can't be resolved
+fn parse_node(node: &str) -> Option<IpAddr> {
+    let node = node
+        .strip_prefix('"')
+        .and_then(|node| node.strip_suffix('"'))
+        .unwrap_or(node);
+
+    if let Some(bracketed) = node.strip_prefix('[') {
+        let (ip, port) = bracketed.split_once(']')?;
+        if !port.is_empty() && !port.strip_prefix(':')?.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+        return ip.parse::<Ipv6Addr>().ok().map(IpAddr::V6);
+    }
+
+    node.parse::<IpAddr>()
+        .or_else(|_| node.parse::<SocketAddr>().map(|addr| addr.ip()))
+        .ok()
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::IpAddr;
+
+    use crate::forwarded::{parse_node, ForwardedHeader, TrustedProxies};
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_x_forwarded_for() {
+        let proxies = TrustedProxies::new(
+            vec!["10.0.0.0/8".parse().unwrap()],
+            ForwardedHeader::XForwardedFor,
+        );
+        let resolve = |peer, header| proxies.client_ip(ip(peer), header);
+
+        assert_eq!(Some(ip("1.2.3.4")), resolve("10.0.0.1", "1.2.3.4"));
+        // the nearest untrusted hop wins, whatever the client claims
+        assert_eq!(
+            Some(ip("5.6.7.8")),
+            resolve("10.0.0.1", "1.2.3.4, 5.6.7.8, 10.0.0.2")
+        );
+        // untrusted peers can't forward anything
+        assert_eq!(Some(ip("9.9.9.9")), resolve("9.9.9.9", "1.2.3.4"));
+        assert_eq!(Some(ip("10.0.0.1")), resolve("10.0.0.1", ""));
+        assert_eq!(
+            Some(ip("1.2.3.4")),
+            resolve("::ffff:10.0.0.1", "1.2.3.4:5000")
+        );
+
+        assert_eq!(None, resolve("10.0.0.1", "1.2.3.4, garbage"));
+        // garbage beyond the first untrusted hop doesn't matter
+        assert_eq!(Some(ip("5.6.7.8")), resolve("10.0.0.1", "garbage, 5.6.7.8"));
+    }
+
+    #[test]
+    fn test_forwarded() {
+        let proxies = TrustedProxies::new(
+            vec!["0.0.0.0/0".parse().unwrap()],
+            ForwardedHeader::Forwarded,
+        );
+        let resolve = |header| proxies.client_ip(ip("10.0.0.1"), header);
+
+        assert_eq!(Some(ip("192.0.2.60")), resolve("for=192.0.2.60;proto=http"));
+        assert_eq!(
+            Some(ip("2001:db8:cafe::17")),
+            resolve(r#"For="[2001:db8:cafe::17]:4711", for=192.0.2.43"#)
+        );
+        assert_eq!(
+            Some(ip("2001:db8::1")),
+            resolve("for=192.0.2.43, for=\"[2001:db8::1]\"")
+        );
+        assert_eq!(None, resolve("for=unknown"));
+        assert_eq!(None, resolve("proto=https;by=10.0.0.2"));
+    }
+
+    #[test]
+    fn test_mixed_headers() {
+        let nets = vec!["10.0.0.0/8".parse().unwrap()];
+        // a client of an X-Forwarded-For proxy sends its own Forwarded
+        let proxies = TrustedProxies::new(nets.clone(), ForwardedHeader::XForwardedFor);
+        assert!(proxies.reads("x-forwarded-for"));
+        assert!(!proxies.reads("forwarded"));
+        assert_eq!(
+            Some(ip("5.6.7.8")),
+            proxies.client_ip(ip("10.0.0.1"), "5.6.7.8")
+        );
+
+        // and the other way around
+        let proxies = TrustedProxies::new(nets, ForwardedHeader::Forwarded);
+        assert!(proxies.reads("forwarded"));
+        assert!(!proxies.reads("x-forwarded-for"));
+        assert_eq!(
+            Some(ip("5.6.7.8")),
+            proxies.client_ip(ip("10.0.0.1"), "for=5.6.7.8")
+        );
+
+        assert_eq!(
+            ForwardedHeader::Forwarded,
+            "Forwarded".parse::<ForwardedHeader>().unwrap()
+        );
+        assert!("x-real-ip".parse::<ForwardedHeader>().is_err());
+    }
+
+    #[test]
+    fn test_parse_node() {
+        assert_eq!(Some(ip("1.2.3.4")), parse_node("\"1.2.3.4:80\""));
+        assert_eq!(Some(ip("2001:db8::1")), parse_node("2001:db8::1"));
+        assert_eq!(None, parse_node("[2001:db8::1]:http"));
+        assert_eq!(None, parse_node("_hidden"));
+        assert_eq!(None, parse_node(""));
+    }
+}
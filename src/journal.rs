@@ -0,0 +1,487 @@
+use std::{
+    collections::VecDeque,
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, BufWriter, Write},
+    net::IpAddr,
+    os::unix::fs::FileExt,
+    path::{Path, PathBuf},
+    sync::{
+        mpsc::{self, Receiver, Sender, TryRecvError},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize};
+use smol_str::SmolStr;
+
+use crate::{
+    ban::BanInfo,
+    shutdown,
+    state::{State, User},
+};
+
+const JOURNAL_FILE: &str = "journal.jsonl";
+// journal being compacted, replayed only if we crashed during compaction
+const OLD_JOURNAL_FILE: &str = "journal.old.jsonl";
+const SNAPSHOT_FILE: &str = "snapshot.jsonl";
+const SNAPSHOT_TMP_FILE: &str = "snapshot.tmp.jsonl";
+
+const SYNC_PERIOD: Duration = Duration::from_secs(1);
+
+/// Journal record. Every record is idempotent, so replaying a record already
+/// included in the snapshot is harmless.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum Mutation {
+    // full user record after creation, edit or ban
+    PutUser {
+        login: SmolStr,
+        password: SmolStr,
+        name: SmolStr,
+        phone: SmolStr,
+        country: SmolStr,
+        is_admin: bool,
+        // records written before bans had details carry only the flag
+        is_banned: bool,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        ban: Option<BanInfo>,
+    },
+    BanSubnet {
+        network: IpAddr,
+        mask: u8,
+        #[serde(default)]
+        ban: BanInfo,
+    },
+    UnbanSubnet {
+        network: IpAddr,
+        mask: u8,
+    },
+}
+
+impl Mutation {
+    pub fn put_user(user: &User) -> Mutation {
+        Mutation::PutUser {
+            login: user.login.clone(),
+            password: user.password.clone(),
+            name: user.name.clone(),
+            phone: user.phone.clone(),
+            country: user.country.clone(),
+            is_admin: user.is_admin,
+            is_banned: user.is_banned(),
+            ban: user.ban.clone(),
+        }
+    }
+
+    fn apply(self, state: &State) -> anyhow::Result<()> {
+        match self {
+            Mutation::PutUser {
+                login,
+                password,
+                name,
+                phone,
+                country,
+                is_admin,
+                is_banned,
+                ban,
+            } => {
+                let user = User {
+                    login,
+                    password,
+                    name,
+                    phone,
+                    country,
+                    is_admin,
+                    ban: ban.or_else(|| is_banned.then(BanInfo::default)),
+                    nonces: VecDeque::new(),
+                    token_generation: 0,
+                };
+                state.put_user(user);
+            }
+            Mutation::BanSubnet { network, mask, ban } => {
+                check_prefix(network, mask)?;
+                state.ban_subnet(network, mask, ban);
+            }
+            Mutation::UnbanSubnet { network, mask } => {
+                check_prefix(network, mask)?;
+                state.unban_subnet(network, mask);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// the state takes subnets from the API already validated
+fn check_prefix(network: IpAddr, mask: u8) -> anyhow::Result<()> {
+    IpNet::new(network, mask).with_context(|| format!("invalid subnet {network}/{mask}"))?;
+    Ok(())
+}
+
+/// Append-only log of state mutations with periodic compaction into a snapshot.
+/// Records are written by a dedicated thread, so callers never block on the disk.
+pub struct Journal {
+    dir: PathBuf,
+    commands: Sender<Command>,
+}
+
+enum Command {
+    Append(Mutation),
+    // replies once everything sent before is on disk
+    Sync(Sender<()>),
+    // moves the journal aside for compaction and starts a fresh one
+    Rotate(Sender<anyhow::Result<()>>),
+}
+
+impl Journal {
+    /// Applies snapshot and journals from `dir` to `state`.
+    /// Must be called before the journal is attached to the state.
+    pub fn replay(dir: &Path, state: &State) -> anyhow::Result<()> {
+        let start = Instant::now();
+
+        let mut applied = 0;
+        for file in [SNAPSHOT_FILE, OLD_JOURNAL_FILE, JOURNAL_FILE] {
+            applied += replay_file(&dir.join(file), state)?;
+        }
+
+        eprintln!(
+            "replay journal: {applied} records, {:?}",
+            Instant::now().duration_since(start)
+        );
+
+        Ok(())
+    }
+
+    pub fn open(dir: &Path) -> anyhow::Result<Journal> {
+        std::fs::create_dir_all(dir)
+            .with_context(|| format!("unable to create state dir {}", dir.display()))?;
+
+        let writer = Writer {
+            dir: dir.to_owned(),
+            file: open_journal(&dir.join(JOURNAL_FILE))?,
+        };
+        let (commands, receiver) = mpsc::channel();
+        std::thread::Builder::new()
+            .name("journal".into())
+            .spawn(move || writer.run(receiver))
+            .context("unable to start journal writer")?;
+
+        Ok(Journal {
+            dir: dir.to_owned(),
+            commands,
+        })
+    }
+
+    /// Queues the record for writing. The caller must hold the lock
+    /// protecting the mutated data, so records of one entity are never reordered.
+    pub fn append(&self, mutation: Mutation) {
+        if let Err(mpsc::SendError(Command::Append(mutation))) =
+            self.commands.send(Command::Append(mutation))
+        {
+            eprintln!("journal writer is gone, record lost {mutation:?}");
+        }
+    }
+
+    /// Waits until all queued records are written and synced.
+    pub fn sync(&self) {
+        let (done, wait) = mpsc::channel();
+        if self.commands.send(Command::Sync(done)).is_err() || wait.recv().is_err() {
+            eprintln!("unable to sync journal: writer is gone");
+        }
+    }
+
+    /// Writes the whole state into a new snapshot and drops journal records it covers.
+    pub fn compact(&self, state: &State) -> anyhow::Result<()> {
+        let start = Instant::now();
+
+        // new records go to a fresh journal while the snapshot is written,
+        // everything queued before is already in the state
+        let (done, wait) = mpsc::channel();
+        self.commands
+            .send(Command::Rotate(done))
+            .ok()
+            .context("journal writer is gone")?;
+        wait.recv().context("journal writer is gone")??;
+
+        let tmp = self.dir.join(SNAPSHOT_TMP_FILE);
+        let mut snapshot = BufWriter::new(File::create(&tmp)?);
+        let mut records = 0;
+        for mutation in state.snapshot() {
+            serde_json::to_writer(&mut snapshot, &mutation)?;
+            snapshot.write_all(b"\n")?;
+            records += 1;
+        }
+        snapshot.flush()?;
+        snapshot.get_ref().sync_all()?;
+        drop(snapshot);
+
+        std::fs::rename(&tmp, self.dir.join(SNAPSHOT_FILE))?;
+        std::fs::remove_file(self.dir.join(OLD_JOURNAL_FILE))?;
+
+        eprintln!(
+            "compact journal: {records} records, {:?}",
+            Instant::now().duration_since(start)
+        );
+
+        Ok(())
+    }
+}
+
+struct Writer {
+    dir: PathBuf,
+    file: BufWriter<File>,
+}
+
+impl Writer {
+    /// Serves commands until the journal is dropped.
+    fn run(mut self, commands: Receiver<Command>) {
+        loop {
+            let command = match commands.try_recv() {
+                Ok(command) => command,
+                // records of a burst share one write
+                Err(TryRecvError::Empty) => {
+                    if let Err(e) = self.file.flush() {
+                        eprintln!("unable to write journal: {e}");
+                    }
+                    match commands.recv() {
+                        Ok(command) => command,
+                        Err(_) => break,
+                    }
+                }
+                Err(TryRecvError::Disconnected) => break,
+            };
+
+            match command {
+                Command::Append(mutation) => {
+                    let res = serde_json::to_writer(&mut self.file, &mutation)
+                        .map_err(std::io::Error::from)
+                        .and_then(|_| self.file.write_all(b"\n"));
+                    if let Err(e) = res {
+                        eprintln!("unable to write journal record {mutation:?}: {e}");
+                    }
+                }
+                Command::Sync(done) => {
+                    let res = self
+                        .file
+                        .flush()
+                        .and_then(|_| self.file.get_ref().sync_data());
+                    if let Err(e) = res {
+                        eprintln!("unable to sync journal: {e}");
+                    }
+                    let _ = done.send(());
+                }
+                Command::Rotate(done) => {
+                    let _ = done.send(self.rotate());
+                }
+            }
+        }
+
+        if let Err(e) = self.file.flush() {
+            eprintln!("unable to write journal: {e}");
+        }
+    }
+
+    fn rotate(&mut self) -> anyhow::Result<()> {
+        let journal = self.dir.join(JOURNAL_FILE);
+        let old_journal = self.dir.join(OLD_JOURNAL_FILE);
+
+        self.file.flush()?;
+        self.file.get_ref().sync_data()?;
+        if old_journal.exists() {
+            // previous compaction didn't finish, its records aren't in the snapshot yet
+            let mut old = OpenOptions::new().append(true).open(&old_journal)?;
+            std::io::copy(&mut File::open(&journal)?, &mut old)?;
+            old.sync_data()?;
+            std::fs::remove_file(&journal)?;
+        } else {
+            std::fs::rename(&journal, &old_journal)?;
+        }
+        self.file = open_journal(&journal)?;
+
+        Ok(())
+    }
+}
+
+fn open_journal(path: &Path) -> anyhow::Result<BufWriter<File>> {
+    let mut file = OpenOptions::new()
+        .create(true)
+        .read(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("unable to open journal {}", path.display()))?;
+
+    // terminate a torn record, otherwise the next one is glued to it
+    let len = file.metadata()?.len();
+    if len > 0 {
+        let mut last = [0u8; 1];
+        file.read_exact_at(&mut last, len - 1)?;
+        if last[0] != b'\n' {
+            file.write_all(b"\n")?;
+        }
+    }
+
+    Ok(BufWriter::new(file))
+}
+
+fn replay_file(path: &Path, state: &State) -> anyhow::Result<usize> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e).with_context(|| format!("unable to open {}", path.display())),
+    };
+
+    let mut applied = 0;
+    for (idx, line) in BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        if line.is_empty() {
+            continue;
+        }
+
+        // the last record may be torn by a crash in the middle of a write
+        let mutation: Mutation = match serde_json::from_str(&line) {
+            Ok(mutation) => mutation,
+            Err(e) => {
+                eprintln!("skip broken record {}:{}: {e}", path.display(), idx + 1);
+                continue;
+            }
+        };
+
+        if let Err(e) = mutation.apply(state) {
+            eprintln!("skip broken record {}:{}: {e:#}", path.display(), idx + 1);
+            continue;
+        }
+        applied += 1;
+    }
+
+    Ok(applied)
+}
+
+/// Background thread syncing the journal every second and compacting it every `period`.
+/// Stops once shutdown is requested, the final compaction is up to the caller.
+pub fn spawn_maintenance(state: Arc<State>, period: Duration) -> std::thread::JoinHandle<()> {
+    std::thread::spawn(move || {
+        let Some(journal) = state.journal() else {
+            return;
+        };
+
+        let mut last_compaction = Instant::now();
+        while !shutdown::is_requested() {
+            std::thread::sleep(SYNC_PERIOD);
+            journal.sync();
+
+            if last_compaction.elapsed() >= period {
+                if let Err(e) = journal.compact(&state) {
+                    eprintln!("unable to compact journal: {e:?}");
+                }
+                last_compaction = Instant::now();
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::Ipv4Addr;
+
+    use crate::{
+        ban::{unix_now, BanInfo},
+        journal::{Journal, Mutation, JOURNAL_FILE},
+        state::{State, TestOptions},
+    };
+
+    #[monoio::test(timer_enabled = true)]
+    async fn test_replay() {
+        let dir = std::env::temp_dir().join(format!("hlfun_journal_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+
+        let state = State::for_test(TestOptions::default());
+        Journal::replay(&dir, &state).unwrap();
+        state.attach_journal(Journal::open(&dir).unwrap());
+
+        state
+            .create_user("abcde", "secret", "name", "phone", "Country")
+            .await;
+        state
+            .create_user("fghij", "secret", "name", "phone", "Country")
+            .await;
+        state.ban_subnet(Ipv4Addr::new(10, 0, 0, 0).into(), 8, BanInfo::default());
+        state.ban_subnet("2001:db8::".parse().unwrap(), 32, BanInfo::default());
+        state.journal().unwrap().compact(&state).unwrap();
+
+        // records after compaction go to the fresh journal
+        let expires_at = unix_now() + 60;
+        let ban = BanInfo {
+            expires_at: Some(expires_at),
+            reason: Some("spam".into()),
+            admin: Some("root".into()),
+        };
+        state.ban_user("abcde", ban.clone());
+        state.ban_subnet(Ipv4Addr::new(192, 168, 0, 0).into(), 16, ban.clone());
+        state.unban_subnet(Ipv4Addr::new(10, 0, 0, 0).into(), 8);
+        state.journal().unwrap().sync();
+
+        let restored = State::for_test(TestOptions::default());
+        Journal::replay(&dir, &restored).unwrap();
+
+        assert_eq!(2, restored.users.len());
+        assert_eq!(
+            Some(&ban),
+            restored.users.get("abcde").unwrap().ban.as_ref()
+        );
+        assert!(!restored.users.get("fghij").unwrap().is_banned());
+        assert_eq!(1, restored.banned_users().len());
+        assert_eq!(
+            state.users.get("fghij").unwrap().password,
+            restored.users.get("fghij").unwrap().password
+        );
+        assert!(restored.is_ip_banned(Ipv4Addr::new(192, 168, 1, 1).into()));
+        assert!(!restored.is_ip_banned(Ipv4Addr::new(10, 1, 1, 1).into()));
+        assert!(restored.is_ip_banned("2001:db8:1::1".parse().unwrap()));
+
+        // expiry timers are restored as well
+        assert_eq!(2, restored.expire_bans(expires_at));
+        assert!(!restored.users.get("abcde").unwrap().is_banned());
+        assert!(!restored.is_ip_banned(Ipv4Addr::new(192, 168, 1, 1).into()));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_replay_invalid_subnet() {
+        let dir = std::env::temp_dir().join(format!("hlfun_journal_bad_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let records = [
+            Mutation::BanSubnet {
+                network: Ipv4Addr::new(10, 0, 0, 0).into(),
+                mask: 33,
+                ban: BanInfo::default(),
+            },
+            Mutation::BanSubnet {
+                network: Ipv4Addr::new(192, 168, 0, 0).into(),
+                mask: 16,
+                ban: BanInfo::default(),
+            },
+            Mutation::UnbanSubnet {
+                network: "2001:db8::".parse().unwrap(),
+                mask: 200,
+            },
+        ];
+        let lines: Vec<_> = records
+            .iter()
+            .map(|record| serde_json::to_string(record).unwrap())
+            .collect();
+        std::fs::write(dir.join(JOURNAL_FILE), lines.join("\n")).unwrap();
+
+        // broken records are skipped instead of taking the startup down
+        let state = State::for_test(TestOptions::default());
+        Journal::replay(&dir, &state).unwrap();
+        assert_eq!(1, state.banned_subnet_rules().len());
+        assert!(state.is_ip_banned(Ipv4Addr::new(192, 168, 1, 1).into()));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
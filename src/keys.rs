@@ -0,0 +1,432 @@
+use std::str::FromStr;
+
+use anyhow::{anyhow, bail, Context};
+use base64::prelude::*;
+use jwt_simple::prelude::*;
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::{blocking, config::JwtConfig};
+
+const RSA_MODULUS_BITS: usize = 2048;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Algorithm {
+    HS256,
+    RS256,
+    ES256,
+    EdDSA,
+}
+
+impl FromStr for Algorithm {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "HS256" => Ok(Algorithm::HS256),
+            "RS256" => Ok(Algorithm::RS256),
+            "ES256" => Ok(Algorithm::ES256),
+            "EdDSA" => Ok(Algorithm::EdDSA),
+            _ => {
+                bail!("unsupported jwt algorithm {s:?}, expected one of HS256, RS256, ES256, EdDSA")
+            }
+        }
+    }
+}
+
+// few long-living instances, no need to box
+#[allow(clippy::large_enum_variant)]
+pub enum SigningKey {
+    HS256(HS256Key),
+    RS256(RS256KeyPair, RS256PublicKey),
+    ES256(ES256KeyPair, ES256PublicKey),
+    EdDSA(Ed25519KeyPair, Ed25519PublicKey),
+}
+
+impl SigningKey {
+    /// Loads the configured inline key or key file.
+    /// HS256 keys are base64 encoded, asymmetric ones are PEM private keys.
+    /// A random key is generated only if `ephemeral_key` is set, tokens signed
+    /// with it don't survive restart and aren't accepted by other processes.
+    pub fn from_config(config: &JwtConfig) -> anyhow::Result<SigningKey> {
+        let alg = config.alg;
+        let material = match (&config.key, &config.key_file) {
+            (Some(key), _) => key.clone(),
+            (None, Some(path)) => std::fs::read_to_string(path)
+                .with_context(|| format!("unable to read jwt key from {}", path.display()))?,
+            (None, None) if config.ephemeral_key => {
+                eprintln!("no jwt key configured, generating random {alg:?} key");
+                return SigningKey::generate(alg);
+            }
+            (None, None) => {
+                bail!("jwt.key or jwt.key_file is required, or set jwt.ephemeral_key")
+            }
+        };
+
+        SigningKey::load(alg, material.trim())
+    }
+
+    pub fn load(alg: Algorithm, material: &str) -> anyhow::Result<SigningKey> {
+        let key = match alg {
+            Algorithm::HS256 => {
+                let key_bytes = BASE64_STANDARD
+                    .decode(material)
+                    .context("HS256 key must be base64 encoded")?;
+                SigningKey::HS256(HS256Key::from_bytes(&key_bytes))
+            }
+            Algorithm::RS256 => {
+                let pair = RS256KeyPair::from_pem(material).map_err(|e| anyhow!("RS256: {e}"))?;
+                let public = pair.public_key();
+                SigningKey::RS256(pair, public)
+            }
+            Algorithm::ES256 => {
+                let pair = ES256KeyPair::from_pem(material).map_err(|e| anyhow!("ES256: {e}"))?;
+                let public = pair.public_key();
+                SigningKey::ES256(pair, public)
+            }
+            Algorithm::EdDSA => {
+                let pair = Ed25519KeyPair::from_pem(material).map_err(|e| anyhow!("EdDSA: {e}"))?;
+                let public = pair.public_key();
+                SigningKey::EdDSA(pair, public)
+            }
+        };
+
+        Ok(key.with_thumbprint_kid())
+    }
+
+    pub fn generate(alg: Algorithm) -> anyhow::Result<SigningKey> {
+        let key = match alg {
+            Algorithm::HS256 => SigningKey::HS256(HS256Key::generate()),
+            Algorithm::RS256 => {
+                let pair = RS256KeyPair::generate(RSA_MODULUS_BITS)?;
+                let public = pair.public_key();
+                SigningKey::RS256(pair, public)
+            }
+            Algorithm::ES256 => {
+                let pair = ES256KeyPair::generate();
+                let public = pair.public_key();
+                SigningKey::ES256(pair, public)
+            }
+            Algorithm::EdDSA => {
+                let pair = Ed25519KeyPair::generate();
+                let public = pair.public_key();
+                SigningKey::EdDSA(pair, public)
+            }
+        };
+
+        Ok(key.with_thumbprint_kid())
+    }
+
+    /// Generates the key on the blocking pool, an RSA key takes long enough
+    /// to stall every connection of the worker.
+    pub async fn generate_off_worker(alg: Algorithm) -> anyhow::Result<SigningKey> {
+        blocking::run(move || SigningKey::generate(alg))
+            .await
+            .with_context(|| format!("{alg:?} key generation"))?
+    }
+
+    pub fn algorithm(&self) -> Algorithm {
+        match self {
+            SigningKey::HS256(_) => Algorithm::HS256,
+            SigningKey::RS256(..) => Algorithm::RS256,
+            SigningKey::ES256(..) => Algorithm::ES256,
+            SigningKey::EdDSA(..) => Algorithm::EdDSA,
+        }
+    }
+
+    pub fn kid(&self) -> Option<&str> {
+        let kid = match self {
+            SigningKey::HS256(key) => key.key_id(),
+            SigningKey::RS256(pair, _) => pair.key_id(),
+            SigningKey::ES256(pair, _) => pair.key_id(),
+            SigningKey::EdDSA(pair, _) => pair.key_id(),
+        };
+
+        kid.as_deref()
+    }
+
+    // kid is derived from the key itself, so it stays the same across restarts
+    // for keys loaded from configuration
+    fn with_thumbprint_kid(self) -> SigningKey {
+        let mut hash = hmac_sha256::Hash::new();
+        hash.update(format!("{:?}", self.algorithm()));
+        match &self {
+            SigningKey::HS256(key) => hash.update(key.to_bytes()),
+            SigningKey::RS256(_, public) => {
+                let components = public.to_components();
+                hash.update(components.n);
+                hash.update(components.e);
+            }
+            SigningKey::ES256(_, public) => hash.update(public.to_bytes()),
+            SigningKey::EdDSA(_, public) => hash.update(public.to_bytes()),
+        }
+        let kid = BASE64_URL_SAFE_NO_PAD.encode(&hash.finalize()[..12]);
+
+        match self {
+            SigningKey::HS256(key) => SigningKey::HS256(key.with_key_id(&kid)),
+            SigningKey::RS256(pair, public) => {
+                SigningKey::RS256(pair.with_key_id(&kid), public.with_key_id(&kid))
+            }
+            SigningKey::ES256(pair, public) => {
+                SigningKey::ES256(pair.with_key_id(&kid), public.with_key_id(&kid))
+            }
+            SigningKey::EdDSA(pair, public) => {
+                SigningKey::EdDSA(pair.with_key_id(&kid), public.with_key_id(&kid))
+            }
+        }
+    }
+
+    pub fn sign<C: Serialize + DeserializeOwned>(
+        &self,
+        claims: JWTClaims<C>,
+    ) -> Result<String, jwt_simple::Error> {
+        match self {
+            SigningKey::HS256(key) => key.authenticate(claims),
+            SigningKey::RS256(pair, _) => pair.sign(claims),
+            SigningKey::ES256(pair, _) => pair.sign(claims),
+            SigningKey::EdDSA(pair, _) => pair.sign(claims),
+        }
+    }
+
+    pub fn verify<C: Serialize + DeserializeOwned>(
+        &self,
+        token: &str,
+        options: Option<VerificationOptions>,
+    ) -> Result<JWTClaims<C>, jwt_simple::Error> {
+        match self {
+            SigningKey::HS256(key) => key.verify_token(token, options),
+            SigningKey::RS256(_, public) => public.verify_token(token, options),
+            SigningKey::ES256(_, public) => public.verify_token(token, options),
+            SigningKey::EdDSA(_, public) => public.verify_token(token, options),
+        }
+    }
+
+    /// Public part of the key as JWK, `None` for symmetric keys.
+    pub fn jwk(&self) -> Option<serde_json::Value> {
+        let b64 = |bytes: &[u8]| BASE64_URL_SAFE_NO_PAD.encode(bytes);
+
+        let mut jwk = match self {
+            SigningKey::HS256(_) => return None,
+            SigningKey::RS256(_, public) => {
+                let components = public.to_components();
+                serde_json::json!({
+                    "kty": "RSA",
+                    "alg": "RS256",
+                    "use": "sig",
+                    "n": b64(&components.n),
+                    "e": b64(&components.e),
+                })
+            }
+            SigningKey::ES256(_, public) => {
+                // SEC1 uncompressed point: 0x04 || x || y
+                let point = public.public_key().to_bytes_uncompressed();
+                serde_json::json!({
+                    "kty": "EC",
+                    "alg": "ES256",
+                    "use": "sig",
+                    "crv": "P-256",
+                    "x": b64(&point[1..33]),
+                    "y": b64(&point[33..65]),
+                })
+            }
+            SigningKey::EdDSA(_, public) => serde_json::json!({
+                "kty": "OKP",
+                "alg": "EdDSA",
+                "use": "sig",
+                "crv": "Ed25519",
+                "x": b64(&public.to_bytes()),
+            }),
+        };
+
+        if let Some(kid) = self.kid() {
+            jwk["kid"] = kid.into();
+        }
+
+        Some(jwk)
+    }
+}
+
+/// Active signing key plus retired keys, which are still accepted for
+/// verification of already issued tokens.
+pub struct KeyRing {
+    // active key is the last one
+    keys: Vec<RingKey>,
+    jwks: String,
+}
+
+struct RingKey {
+    key: SigningKey,
+    retired_at: Option<UnixTimeStamp>,
+}
+
+impl KeyRing {
+    pub fn new(key: SigningKey) -> KeyRing {
+        let mut ring = KeyRing {
+            keys: vec![RingKey {
+                key,
+                retired_at: None,
+            }],
+            jwks: String::new(),
+        };
+        ring.update_jwks();
+        ring
+    }
+
+    pub fn active(&self) -> &SigningKey {
+        &self.keys.last().expect("key ring is never empty").key
+    }
+
+    /// Key for verification of a token with given `kid`. Tokens without `kid`
+    /// were issued before rotation support and are checked with the active key.
+    pub fn find(&self, kid: Option<&str>) -> Option<&SigningKey> {
+        match kid {
+            Some(kid) => self
+                .keys
+                .iter()
+                .rev()
+                .map(|stored| &stored.key)
+                .find(|key| key.kid() == Some(kid)),
+            None => Some(self.active()),
+        }
+    }
+
+    /// Makes `key` active. Returns `false` if the key is already in the ring.
+    /// Keys retired more than `retain_for` ago are dropped, tokens signed by
+    /// them are expired anyway.
+    pub fn rotate(&mut self, key: SigningKey, retain_for: Duration) -> bool {
+        if self.keys.iter().any(|stored| stored.key.kid() == key.kid()) {
+            return false;
+        }
+
+        let now = Clock::now_since_epoch();
+        if let Some(active) = self.keys.last_mut() {
+            active.retired_at = Some(now);
+        }
+
+        self.keys.retain(|stored| match stored.retired_at {
+            Some(at) => now - at < retain_for,
+            None => true,
+        });
+        self.keys.push(RingKey {
+            key,
+            retired_at: None,
+        });
+        self.update_jwks();
+        true
+    }
+
+    pub fn jwks(&self) -> &str {
+        &self.jwks
+    }
+
+    fn update_jwks(&mut self) {
+        self.jwks = jwks(self.keys.iter().map(|stored| &stored.key));
+    }
+}
+
+/// JWKS document with public keys of `keys`.
+pub fn jwks<'a>(keys: impl Iterator<Item = &'a SigningKey>) -> String {
+    let keys: Vec<_> = keys.filter_map(SigningKey::jwk).collect();
+    serde_json::json!({ "keys": keys }).to_string()
+}
+
+#[cfg(test)]
+mod test {
+    use jwt_simple::prelude::*;
+
+    use crate::{
+        config::JwtConfig,
+        keys::{jwks, Algorithm, KeyRing, SigningKey},
+    };
+
+    #[test]
+    fn test_sign_verify() {
+        for alg in [Algorithm::HS256, Algorithm::ES256, Algorithm::EdDSA] {
+            let key = SigningKey::generate(alg).unwrap();
+            let token = key
+                .sign(Claims::create(Duration::from_mins(1)).with_subject("abcde"))
+                .unwrap();
+
+            let claims = key.verify::<NoCustomClaims>(&token, None).unwrap();
+            assert_eq!(Some("abcde".to_string()), claims.subject);
+
+            let other = SigningKey::generate(alg).unwrap();
+            assert!(other.verify::<NoCustomClaims>(&token, None).is_err());
+        }
+    }
+
+    #[test]
+    fn test_jwks() {
+        let hs = SigningKey::generate(Algorithm::HS256).unwrap();
+        let es = SigningKey::generate(Algorithm::ES256).unwrap();
+        let ed = SigningKey::generate(Algorithm::EdDSA).unwrap();
+
+        let doc: serde_json::Value =
+            serde_json::from_str(&jwks([&hs, &es, &ed].into_iter())).unwrap();
+        let keys = doc["keys"].as_array().unwrap();
+
+        // symmetric keys are never published
+        assert_eq!(2, keys.len());
+        assert_eq!("P-256", keys[0]["crv"]);
+        assert_eq!("Ed25519", keys[1]["crv"]);
+        assert_eq!(es.kid().unwrap(), keys[0]["kid"]);
+    }
+
+    #[test]
+    fn test_rotate() {
+        let first = SigningKey::generate(Algorithm::EdDSA).unwrap();
+        let first_kid = first.kid().unwrap().to_string();
+        let mut ring = KeyRing::new(first);
+
+        let token = ring
+            .active()
+            .sign(Claims::create(Duration::from_mins(1)))
+            .unwrap();
+        let metadata = Token::decode_metadata(&token).unwrap();
+        assert_eq!(Some(first_kid.as_str()), metadata.key_id());
+
+        let second = SigningKey::generate(Algorithm::EdDSA).unwrap();
+        let second_kid = second.kid().unwrap().to_string();
+        assert!(ring.rotate(second, Duration::from_hours(1)));
+        assert_eq!(Some(second_kid.as_str()), ring.active().kid());
+
+        // tokens signed before rotation are still verified with the retired key
+        let key = ring.find(metadata.key_id()).unwrap();
+        assert!(key.verify::<NoCustomClaims>(&token, None).is_ok());
+        assert!(ring
+            .active()
+            .verify::<NoCustomClaims>(&token, None)
+            .is_err());
+
+        assert!(ring.find(Some("unknown")).is_none());
+        assert_eq!(Some(second_kid.as_str()), ring.find(None).unwrap().kid());
+
+        // retired keys outlive tokens signed by them only by `retain_for`
+        let third = SigningKey::generate(Algorithm::EdDSA).unwrap();
+        assert!(ring.rotate(third, Duration::from_secs(0)));
+        assert!(ring.find(Some(first_kid.as_str())).is_none());
+
+        let reloaded = SigningKey::load(
+            Algorithm::HS256,
+            "CGWpjarkRIXzCIIw5vXKc+uESy5ebrbOyVMZvftj19k=",
+        );
+        let again = SigningKey::load(
+            Algorithm::HS256,
+            "CGWpjarkRIXzCIIw5vXKc+uESy5ebrbOyVMZvftj19k=",
+        );
+        assert_eq!(reloaded.unwrap().kid(), again.unwrap().kid());
+    }
+
+    #[test]
+    fn test_from_config() {
+        let mut config = JwtConfig::default();
+        assert!(SigningKey::from_config(&config).is_err());
+
+        config.ephemeral_key = true;
+        assert!(SigningKey::from_config(&config).is_ok());
+
+        config.key = Some("CGWpjarkRIXzCIIw5vXKc+uESy5ebrbOyVMZvftj19k=".into());
+        let key = SigningKey::from_config(&config).unwrap();
+        let again = SigningKey::from_config(&config).unwrap();
+        assert_eq!(key.kid(), again.kid());
+    }
+}
@@ -1,125 +1,420 @@
+mod auth_throttle;
+mod ban;
+mod blacklist;
+mod blocking;
+mod chunked;
+mod config;
+mod forwarded;
+mod journal;
+mod keys;
+mod rate_limit;
 mod service;
+mod session;
 mod sharded_prefix_set;
+mod shutdown;
 mod state;
+mod timer_wheel;
 mod user;
 mod request;
+mod response;
 
 use std::{
+    cell::Cell,
     collections::HashMap,
     net::SocketAddr,
-    sync::{
-        atomic::{AtomicBool, Ordering},
-        Arc,
-    },
+    path::Path,
+    rc::Rc,
+    str::FromStr,
+    sync::Arc,
+    task::{Poll, Waker},
+    thread::JoinHandle,
     time::{Duration, Instant},
 };
 
+use anyhow::{anyhow, Context};
+use config::{Config, Driver, Limits};
 use dashmap::DashMap;
-use iprange::IpRange;
-use monoio::net::{TcpListener, TcpStream};
+use forwarded::TrustedProxies;
+use journal::Journal;
+use keys::SigningKey;
+use http::StatusCode;
+use monoio::{
+    io::AsyncWriteRentExt,
+    net::{TcpListener, TcpStream},
+};
+use response::Response;
 use service::ConnectionProcessor;
 use smol_str::SmolStr;
-use state::{State, User};
+use state::{CountryPrefixes, State, User};
 
 #[global_allocator]
 static GLOBAL: jemallocator::Jemalloc = jemallocator::Jemalloc;
 
-static running: std::sync::atomic::AtomicBool = AtomicBool::new(true);
+// pause after a failed accept, e.g. when out of file descriptors
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+// how often expired bans are lifted, stale login failures and rate limits dropped
+const BAN_EXPIRY_PERIOD: Duration = Duration::from_secs(1);
+// over the connection limit: how many 503 answers may be in flight per listener,
+// beyond that connections are just closed, and how long each may take
+const MAX_REFUSING: usize = 64;
+const REFUSE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Number of connections alive on the current worker.
+#[derive(Clone, Default)]
+struct Connections(Rc<ConnectionCount>);
+
+#[derive(Default)]
+struct ConnectionCount {
+    count: Cell<usize>,
+    // woken once the last connection is closed
+    drained: Cell<Option<Waker>>,
+}
 
-pub async fn serve_http<A>(addr: A, state: Arc<State>) -> std::io::Result<()>
-where
-    A: Into<SocketAddr>,
-{
-    monoio::spawn(async {
-        monoio::time::sleep(Duration::from_secs(60)).await;
-        running.store(false, Ordering::Relaxed);
-    });
-    let listener = TcpListener::bind(addr.into())?;
+struct ConnectionGuard(Connections);
+
+impl Connections {
+    fn enter(&self) -> ConnectionGuard {
+        self.0.count.set(self.0.count.get() + 1);
+        ConnectionGuard(self.clone())
+    }
+
+    fn count(&self) -> usize {
+        self.0.count.get()
+    }
+
+    /// Resolves once all connections are closed.
+    async fn drained(&self) {
+        std::future::poll_fn(|cx| {
+            if self.count() == 0 {
+                return Poll::Ready(());
+            }
+            self.0.drained.set(Some(cx.waker().clone()));
+            Poll::Pending
+        })
+        .await
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        let connections = &self.0 .0;
+        connections.count.set(connections.count.get() - 1);
+        if connections.count.get() == 0 {
+            if let Some(waker) = connections.drained.take() {
+                waker.wake();
+            }
+        }
+    }
+}
+
+/// Settings shared by all workers.
+#[derive(Clone)]
+struct Worker {
+    listen: Vec<SocketAddr>,
+    driver: Driver,
+    limits: Limits,
+    trusted_proxies: Arc<TrustedProxies>,
+    // 0 for no limit
+    max_connections: usize,
+    shutdown_timeout: Duration,
+    // only one worker lifts expired bans
+    expire_bans: bool,
+}
+
+impl Worker {
+    fn run(self, state: Arc<State>) -> anyhow::Result<()> {
+        let driver = self.driver;
+        let body = self.serve_http(state);
+
+        match driver {
+            Driver::Fusion => monoio::RuntimeBuilder::<monoio::FusionDriver>::new()
+                .enable_timer()
+                .build()
+                .context("unable to build fusion runtime")?
+                .block_on(body),
+            Driver::IoUring => monoio::RuntimeBuilder::<monoio::IoUringDriver>::new()
+                .enable_timer()
+                .build()
+                .context("unable to build io_uring runtime")?
+                .block_on(body),
+        }
+    }
+
+    async fn serve_http(self, state: Arc<State>) -> anyhow::Result<()> {
+        // every worker binds every address, SO_REUSEPORT spreads connections between them
+        let mut listeners = Vec::with_capacity(self.listen.len());
+        for addr in self.listen.iter() {
+            let listener =
+                TcpListener::bind(*addr).with_context(|| format!("unable to bind {addr}"))?;
+            listeners.push(listener);
+        }
+
+        if self.expire_bans {
+            monoio::spawn(expire_bans(state.clone()));
+        }
+
+        let connections = Connections::default();
+        let accept_loops: Vec<_> = listeners
+            .into_iter()
+            .map(|listener| {
+                monoio::spawn(accept_loop(
+                    listener,
+                    state.clone(),
+                    self.limits,
+                    self.trusted_proxies.clone(),
+                    self.max_connections,
+                    connections.clone(),
+                ))
+            })
+            .collect();
+
+        for accept_loop in accept_loops {
+            accept_loop.await;
+        }
+
+        // give in-flight requests a chance to complete, the rest are dropped with the runtime
+        let drained = monoio::time::timeout(self.shutdown_timeout, connections.drained()).await;
+        if drained.is_err() {
+            eprintln!("shutdown: dropping {} connections", connections.count());
+        }
 
-    while running.load(std::sync::atomic::Ordering::Relaxed) {
-        let (stream, _) = listener.accept().await?;
-        monoio::spawn(handle_connection(stream, state.clone()));
+        Ok(())
     }
+}
 
-    Ok(())
+async fn accept_loop(
+    listener: TcpListener,
+    state: Arc<State>,
+    limits: Limits,
+    trusted_proxies: Arc<TrustedProxies>,
+    max_connections: usize,
+    connections: Connections,
+) {
+    let refusing = Connections::default();
+    loop {
+        let accepted = monoio::select! {
+            accepted = listener.accept() => accepted,
+            _ = shutdown::wait() => return,
+        };
+        let (stream, peer) = match accepted {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                eprintln!("accept: {e}");
+                monoio::select! {
+                    _ = monoio::time::sleep(ACCEPT_BACKOFF) => continue,
+                    _ = shutdown::wait() => return,
+                }
+            }
+        };
+        if max_connections > 0 && connections.count() >= max_connections {
+            if refusing.count() < MAX_REFUSING {
+                monoio::spawn(refuse_connection(stream, refusing.enter()));
+            }
+            continue;
+        }
+        let guard = connections.enter();
+        monoio::spawn(handle_connection(
+            stream,
+            peer,
+            state.clone(),
+            limits,
+            trusted_proxies.clone(),
+            guard,
+        ));
+    }
+}
+
+// tells the client to come back later instead of a silent reset
+async fn refuse_connection(mut stream: TcpStream, _guard: ConnectionGuard) {
+    let mut out = Vec::new();
+    Response::new(&mut out, StatusCode::SERVICE_UNAVAILABLE, false).empty();
+    let _ = monoio::time::timeout(REFUSE_TIMEOUT, stream.write_all(out)).await;
+}
+
+async fn expire_bans(state: Arc<State>) {
+    loop {
+        monoio::select! {
+            _ = monoio::time::sleep(BAN_EXPIRY_PERIOD) => {},
+            _ = shutdown::wait() => return,
+        };
+
+        let now = ban::unix_now();
+        let lifted = state.expire_bans(now);
+        if lifted > 0 {
+            eprintln!("lifted {lifted} expired bans");
+        }
+        state.auth_throttle.prune(now);
+        state.rate_limiter.prune();
+    }
 }
 
-pub async fn handle_connection(stream: TcpStream, state: Arc<State>) {
-    let mut cp = ConnectionProcessor::new(state, stream);
+async fn handle_connection(
+    stream: TcpStream,
+    peer: SocketAddr,
+    state: Arc<State>,
+    limits: Limits,
+    trusted_proxies: Arc<TrustedProxies>,
+    _guard: ConnectionGuard,
+) {
+    let mut cp = ConnectionProcessor::new(state, stream, peer, limits, trusted_proxies);
     if let Err(e) = cp.process().await {
         eprintln!("error on process connection: {e:?}");
     }
 }
 
 fn main() {
+    let config = match Config::load() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("invalid configuration: {e:#}");
+            std::process::exit(1);
+        }
+    };
+
+    if let Err(e) = run(config) {
+        eprintln!("{e:#}");
+        std::process::exit(1);
+    }
+}
+
+fn run(config: Config) -> anyhow::Result<()> {
     eprintln!(
         "thp: {:?}",
         std::fs::read_to_string("/sys/kernel/mm/transparent_hugepage/enabled")
     );
     // eprintln!("io_uring: {}", monoio::utils::detect_uring());
-    let prefixes = std::thread::spawn(|| read_countries());
-    let users = read_users();
-    let prefixes = prefixes.join().unwrap();
-
-    let state = Arc::new(State::new(users, prefixes));
+    let key = SigningKey::from_config(&config.jwt).context("unable to load jwt signing key")?;
+    shutdown::listen_signals().context("unable to install signal handlers")?;
+
+    let prefixes = {
+        let locations = config.data.geo_locations.clone();
+        let blocks_v4 = config.data.geo_blocks_ipv4.clone();
+        let blocks_v6 = config.data.geo_blocks_ipv6.clone();
+        std::thread::spawn(move || read_countries(&locations, &blocks_v4, &blocks_v6))
+    };
+    let users = read_users(&config.data.users)?;
+    let prefixes = prefixes
+        .join()
+        .map_err(|_| anyhow!("country reader panicked"))??;
+
+    let state = Arc::new(State::new(
+        users,
+        prefixes,
+        key,
+        config.token_settings(),
+        config.auth,
+        &config.rate_limits,
+    ));
+    let maintenance = match &config.data.state_dir {
+        Some(dir) => Some(
+            open_journal(dir, config.snapshot_period(), &state)
+                .context("unable to open journal")?,
+        ),
+        None => None,
+    };
     // let state = Arc::new(State::new(DashMap::new(), HashMap::new()));
-    let state_cln = state.clone();
-    let body = async {
-        let _ = serve_http(([0, 0, 0, 0], 8080), state_cln).await;
+
+    let worker = Worker {
+        listen: config.server.listen.clone(),
+        driver: config.server.driver,
+        limits: config.limits,
+        trusted_proxies: Arc::new(TrustedProxies::new(
+            config.server.trusted_proxies.clone(),
+            config.server.forwarded_header,
+        )),
+        max_connections: config.server.max_connections_per_worker,
+        shutdown_timeout: config.shutdown_timeout(),
+        expire_bans: false,
     };
+    let workers = config.workers();
+    eprintln!("workers: {workers}, listen: {:?}", worker.listen);
 
+    // the main thread is a worker too
     #[allow(clippy::needless_collect)]
-    let threads: Vec<_> = (1..2u32)
+    let threads: Vec<_> = (1..workers)
         .map(|_| {
             let state_cln = state.clone();
-            ::std::thread::spawn(|| {
-                monoio::RuntimeBuilder::<monoio::FusionDriver>::new()
-                // monoio::RuntimeBuilder::<monoio::IoUringDriver>::new()
-                    .enable_timer()
-                    .build()
-                    .expect("Failed building the Runtime")
-                    .block_on(async move {
-                        let _ = serve_http(([0, 0, 0, 0], 8080), state_cln).await;
-                    });
-            })
+            let worker = worker.clone();
+            ::std::thread::spawn(move || worker.run(state_cln))
         })
         .collect();
 
-    monoio::RuntimeBuilder::<monoio::FusionDriver>::new()
-        .enable_timer()
-        .build()
-        .expect("Failed building the Runtime")
-        .block_on(body);
+    let worker = Worker {
+        expire_bans: true,
+        ..worker
+    };
+    let mut result = worker.run(state.clone());
+    // a failed worker takes the others down instead of leaving a half-working server
+    if result.is_err() {
+        shutdown::request();
+    }
 
-    //std::thread::sleep(std::time::Duration::from_secs(60));
+    for t in threads {
+        let res = t
+            .join()
+            .map_err(|_| anyhow!("worker panicked"))
+            .and_then(|res| res);
+        if let Err(e) = res {
+            shutdown::request();
+            if result.is_ok() {
+                result = Err(e);
+            }
+        }
+    }
 
-    // running.store(false, std::sync::atomic::Ordering::Relaxed);
+    // all workers are stopped, the state won't change anymore
+    shutdown::request();
+    if let Some(maintenance) = maintenance {
+        let _ = maintenance.join();
+    }
+    if let Some(journal) = state.journal() {
+        journal
+            .compact(&state)
+            .context("unable to flush state on shutdown")?;
+    }
+
+    result
+}
 
-    threads.into_iter().for_each(|t| {
-        let _ = t.join();
-    });
+fn open_journal(
+    dir: &Path,
+    period: Duration,
+    state: &Arc<State>,
+) -> anyhow::Result<JoinHandle<()>> {
+    Journal::replay(dir, state)?;
+    state.attach_journal(Journal::open(dir)?);
+    Ok(journal::spawn_maintenance(state.clone(), period))
 }
 
-fn read_users() -> DashMap<SmolStr, User> {
+fn read_users(path: &Path) -> anyhow::Result<DashMap<SmolStr, User>> {
     let start = Instant::now();
 
-    let values = std::fs::read_to_string("/storage/data/users.jsonl").unwrap();
+    let values = std::fs::read_to_string(path)
+        .with_context(|| format!("unable to read users from {}", path.display()))?;
     let result = DashMap::with_shard_amount(16);
-    for line in values.trim().split("\n") {
-        let user: User = serde_json::from_str(line).unwrap();
+    for (idx, line) in values.trim().split("\n").enumerate() {
+        let user: User = serde_json::from_str(line)
+            .with_context(|| format!("invalid user at {}:{}", path.display(), idx + 1))?;
         result.insert(user.login.clone(), user);
     }
 
     eprintln!("creating users: {:?}", Instant::now().duration_since(start));
     eprintln!("users-len:{}", result.len());
 
-    result
+    Ok(result)
 }
 
-fn read_countries() -> HashMap<SmolStr, IpRange<ipnet::Ipv4Net>> {
-    let handl = std::thread::spawn(|| {
-        std::fs::read_to_string("/storage/data/GeoLite2-City-CSV/GeoLite2-City-Blocks-IPv4.csv")
-    });
+fn read_countries(
+    locations: &Path,
+    blocks_v4: &Path,
+    blocks_v6: &Path,
+) -> anyhow::Result<HashMap<SmolStr, CountryPrefixes>> {
+    let read = |path: &Path| {
+        let path = path.to_owned();
+        std::thread::spawn(move || std::fs::read_to_string(path))
+    };
+    let handl_v4 = read(blocks_v4);
+    let handl_v6 = read(blocks_v6);
 
     let start = Instant::now();
     let mut country_set: HashMap<SmolStr, SmolStr> = HashMap::new();
@@ -130,14 +425,15 @@ fn read_countries() -> HashMap<SmolStr, IpRange<ipnet::Ipv4Net>> {
     //         .unwrap();
     // let lines = locations.trim().split("\n");
 
-    let mut rdr =
-        csv::Reader::from_path("/storage/data/GeoLite2-City-CSV/GeoLite2-City-Locations-en.csv")
-            .unwrap();
+    let mut rdr = csv::Reader::from_path(locations)
+        .with_context(|| format!("unable to read locations from {}", locations.display()))?;
     for line in rdr.records() {
-        let line = line.unwrap();
+        let line = line.with_context(|| format!("invalid locations {}", locations.display()))?;
 
-        let geo_name_id: SmolStr = line.get(0).unwrap().into();
-        let country = line.get(5).unwrap();
+        let (Some(geo_name_id), Some(country)) = (line.get(0), line.get(5)) else {
+            anyhow::bail!("short location record {line:?} in {}", locations.display());
+        };
+        let geo_name_id: SmolStr = geo_name_id.into();
 
         // let geo_name_id: SmolStr = line.next().unwrap().into();
         // let country = line.skip(4).next().unwrap().trim_matches('"');
@@ -155,51 +451,53 @@ fn read_countries() -> HashMap<SmolStr, IpRange<ipnet::Ipv4Net>> {
 
     eprintln!("creating map: {:?}", Instant::now().duration_since(start));
 
-    let data = handl.join().unwrap().unwrap();
+    let data_v4 = handl_v4
+        .join()
+        .map_err(|_| anyhow!("blocks reader panicked"))?
+        .with_context(|| format!("unable to read blocks from {}", blocks_v4.display()))?;
+    let data_v6 = match handl_v6
+        .join()
+        .map_err(|_| anyhow!("blocks reader panicked"))?
+    {
+        Ok(data) => data,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            eprintln!(
+                "{} not found, IPv6 geo checks always fail",
+                blocks_v6.display()
+            );
+            String::new()
+        }
+        Err(e) => {
+            return Err(e)
+                .with_context(|| format!("unable to read blocks from {}", blocks_v6.display()))
+        }
+    };
     eprintln!("read_blocks: {:?}", Instant::now().duration_since(start));
 
     let start = Instant::now();
-    let mut country_prefixes: HashMap<_, Vec<ipnet::Ipv4Net>> = HashMap::new();
-
-    let lines = data.trim().split("\n");
-    for (_idx, line) in lines.skip(1).enumerate() {
-        let mut line = line.split(",");
-        let cidr = line.next().unwrap();
-        let geoname_id = line.next().unwrap();
-
-        // if idx == 2620546 {
-        //   eprintln!("{cidr}: {geoname_id}");
-        // }
-
-        let Some(country) = geoname_id_by_country.get(geoname_id) else {
-            // eprintln!("not found geoname_id: {geoname_id}");
-            continue;
-        };
-
-        let _cntr = country.as_str();
-        let entry = country_prefixes
-            .entry(country.clone())
-            .or_insert_with(|| Vec::with_capacity(1000));
-
-        entry.push(cidr.parse().unwrap());
-    }
+    let prefixes_v4 = read_blocks(blocks_v4, &data_v4, &geoname_id_by_country)?;
+    let prefixes_v6 = read_blocks(blocks_v6, &data_v6, &geoname_id_by_country)?;
 
     eprintln!(
         "parse prefix_map to vec: {:?}",
         Instant::now().duration_since(start)
     );
 
-    let mut result = HashMap::new();
-    for (k, v) in country_prefixes {
+    let mut result: HashMap<SmolStr, CountryPrefixes> = HashMap::new();
+    for (k, v) in prefixes_v4 {
         // if k.as_str() == "Bonaire, Sint Eustatius, and Saba" {
         //     eprintln!("{:?}", v);
         // }
-        let mut ps = IpRange::new();
+        let ps = &mut result.entry(k).or_default().v4;
+        for net in v {
+            ps.add(net);
+        }
+    }
+    for (k, v) in prefixes_v6 {
+        let ps = &mut result.entry(k).or_default().v6;
         for net in v {
             ps.add(net);
         }
-
-        result.insert(k, ps);
     }
 
     eprintln!(
@@ -207,5 +505,46 @@ fn read_countries() -> HashMap<SmolStr, IpRange<ipnet::Ipv4Net>> {
         Instant::now().duration_since(start)
     );
 
-    result
+    Ok(result)
+}
+
+// networks of GeoLite2 blocks csv grouped by country
+fn read_blocks<N>(
+    path: &Path,
+    data: &str,
+    geoname_id_by_country: &HashMap<SmolStr, SmolStr>,
+) -> anyhow::Result<HashMap<SmolStr, Vec<N>>>
+where
+    N: FromStr,
+    N::Err: std::error::Error + Send + Sync + 'static,
+{
+    let mut country_prefixes: HashMap<_, Vec<N>> = HashMap::new();
+
+    let lines = data.trim().split("\n");
+    for (idx, line) in lines.enumerate().skip(1) {
+        let mut line = line.split(",");
+        let (Some(cidr), Some(geoname_id)) = (line.next(), line.next()) else {
+            anyhow::bail!("short block record at {}:{}", path.display(), idx + 1);
+        };
+
+        // if idx == 2620546 {
+        //   eprintln!("{cidr}: {geoname_id}");
+        // }
+
+        let Some(country) = geoname_id_by_country.get(geoname_id) else {
+            // eprintln!("not found geoname_id: {geoname_id}");
+            continue;
+        };
+
+        let entry = country_prefixes
+            .entry(country.clone())
+            .or_insert_with(|| Vec::with_capacity(1000));
+
+        entry.push(
+            cidr.parse()
+                .with_context(|| format!("invalid network at {}:{}", path.display(), idx + 1))?,
+        );
+    }
+
+    Ok(country_prefixes)
 }
@@ -0,0 +1,259 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+use dashmap::DashMap;
+use smol_str::SmolStr;
+
+use crate::{
+    config::{RateLimit, RateLimitConfig},
+    request::Route,
+};
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Client {
+    Ip(IpAddr),
+    Login(SmolStr),
+}
+
+/// Bucket state after a request, reported in `X-RateLimit-*` headers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Quota {
+    pub limit: u32,
+    pub remaining: u32,
+    // until the bucket is full again
+    pub reset: Duration,
+    // set if the request is rejected
+    pub retry_after: Option<Duration>,
+}
+
+impl Quota {
+    /// The one to report when a request is checked against several buckets.
+    pub fn tighter(self, other: Quota) -> Quota {
+        match (self.retry_after, other.retry_after) {
+            (Some(a), Some(b)) if b > a => other,
+            (Some(_), _) => self,
+            (None, Some(_)) => other,
+            (None, None) if other.remaining < self.remaining => other,
+            (None, None) => self,
+        }
+    }
+}
+
+// about 146 years, so `tat` never overflows: it stays below now + tolerance + interval
+const MAX_TOLERANCE: u64 = u64::MAX / 4;
+
+// GCRA form of the token bucket, times are nanoseconds since the limiter epoch
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    burst: u32,
+    // refill time of one token
+    interval: u64,
+    // refill time of the whole bucket
+    tolerance: u64,
+}
+
+impl Bucket {
+    fn new(limit: RateLimit) -> Bucket {
+        let interval = ((1e9 / limit.rate) as u64).clamp(1, MAX_TOLERANCE / limit.burst as u64);
+        Bucket {
+            burst: limit.burst,
+            interval,
+            tolerance: interval * limit.burst as u64,
+        }
+    }
+
+    // `tat` is the time the bucket is full again
+    fn acquire(&self, tat: &AtomicU64, now: u64) -> Quota {
+        let mut current = tat.load(Ordering::Relaxed);
+        loop {
+            let start = current.max(now);
+            let next = start + self.interval;
+            if next - now > self.tolerance {
+                return Quota {
+                    limit: self.burst,
+                    remaining: 0,
+                    reset: Duration::from_nanos(start - now),
+                    retry_after: Some(Duration::from_nanos(next - self.tolerance - now)),
+                };
+            }
+
+            match tat.compare_exchange_weak(current, next, Ordering::Relaxed, Ordering::Relaxed) {
+                Ok(_) => {
+                    return Quota {
+                        limit: self.burst,
+                        remaining: ((self.tolerance - (next - now)) / self.interval) as u32,
+                        reset: Duration::from_nanos(next - now),
+                        retry_after: None,
+                    }
+                }
+                Err(actual) => current = actual,
+            }
+        }
+    }
+}
+
+/// Token buckets per route and client shared by all workers.
+///
+/// The whole state of a bucket is a single atomic, requests of one client
+/// contend on it without locks; the map is locked only to add a bucket.
+pub struct RateLimiter {
+    default: Option<Bucket>,
+    routes: HashMap<Route, Bucket>,
+    buckets: DashMap<(Route, Client), AtomicU64>,
+    epoch: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(config: &RateLimitConfig) -> RateLimiter {
+        RateLimiter {
+            default: config.default.map(Bucket::new),
+            routes: config
+                .routes
+                .iter()
+                .map(|(&route, &limit)| (route, Bucket::new(limit)))
+                .collect(),
+            buckets: DashMap::new(),
+            epoch: Instant::now(),
+        }
+    }
+
+    fn now(&self) -> u64 {
+        self.epoch.elapsed().as_nanos() as u64
+    }
+
+    /// Takes a token of `client` for `route`, `None` if the route isn't limited.
+    pub fn acquire(&self, route: Route, client: Client) -> Option<Quota> {
+        let bucket = self.routes.get(&route).or(self.default.as_ref())?;
+        let now = self.now();
+
+        let key = (route, client);
+        let quota = match self.buckets.get(&key) {
+            Some(tat) => bucket.acquire(&tat, now),
+            // a fresh bucket is full
+            None => bucket.acquire(&self.buckets.entry(key).or_default(), now),
+        };
+
+        Some(quota)
+    }
+
+    /// Drops full buckets, they are no different from missing ones.
+    pub fn prune(&self) {
+        let now = self.now();
+        self.buckets
+            .retain(|_, tat| tat.load(Ordering::Relaxed) > now);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{collections::HashMap, time::Duration};
+
+    use crate::{
+        config::{RateLimit, RateLimitConfig},
+        rate_limit::{Client, Quota, RateLimiter},
+        request::Route,
+    };
+
+    #[test]
+    fn test_acquire() {
+        let config = RateLimitConfig {
+            default: None,
+            routes: HashMap::from([(
+                Route::Auth,
+                RateLimit {
+                    rate: 0.001,
+                    burst: 3,
+                },
+            )]),
+        };
+        let limiter = RateLimiter::new(&config);
+        let client = Client::Ip("1.2.3.4".parse().unwrap());
+
+        assert_eq!(None, limiter.acquire(Route::GetUser, client.clone()));
+
+        let remaining: Vec<_> = (0..3)
+            .map(|_| limiter.acquire(Route::Auth, client.clone()).unwrap())
+            .map(|quota| quota.remaining)
+            .collect();
+        assert_eq!(vec![2, 1, 0], remaining);
+
+        // a token comes back in 1000 seconds
+        let rejected = limiter.acquire(Route::Auth, client.clone()).unwrap();
+        let retry_after = rejected.retry_after.unwrap();
+        assert!(retry_after > Duration::from_secs(990) && retry_after <= Duration::from_secs(1000));
+
+        // buckets are per client
+        let other = Client::Login("abcde".into());
+        let quota = limiter.acquire(Route::Auth, other).unwrap();
+        assert_eq!(None, quota.retry_after);
+
+        limiter.prune();
+        assert_eq!(2, limiter.buckets.len());
+    }
+
+    #[test]
+    fn test_refill() {
+        let config = RateLimitConfig {
+            default: Some(RateLimit {
+                rate: 100.0,
+                burst: 1,
+            }),
+            routes: HashMap::new(),
+        };
+        let limiter = RateLimiter::new(&config);
+        let client = Client::Ip("::1".parse().unwrap());
+        let allowed = || {
+            let quota = limiter.acquire(Route::Jwks, client.clone()).unwrap();
+            quota.retry_after.is_none()
+        };
+
+        assert!(allowed());
+        assert!(!allowed());
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(allowed());
+
+        std::thread::sleep(Duration::from_millis(20));
+        limiter.prune();
+        assert!(limiter.buckets.is_empty());
+    }
+
+    #[test]
+    fn test_slow_rate() {
+        let config = RateLimitConfig {
+            default: Some(RateLimit {
+                rate: 1e-12,
+                burst: 2,
+            }),
+            routes: HashMap::new(),
+        };
+        let limiter = RateLimiter::new(&config);
+        let client = Client::Ip("1.2.3.4".parse().unwrap());
+
+        let quotas: Vec<_> = (0..3)
+            .map(|_| limiter.acquire(Route::Auth, client.clone()).unwrap())
+            .collect();
+        assert_eq!(None, quotas[0].retry_after);
+        assert!(quotas[2].retry_after.is_some());
+    }
+
+    #[test]
+    fn test_tighter() {
+        let quota = |remaining, retry_after: Option<u64>| Quota {
+            limit: 10,
+            remaining,
+            reset: Duration::from_secs(1),
+            retry_after: retry_after.map(Duration::from_secs),
+        };
+
+        assert_eq!(quota(2, None), quota(5, None).tighter(quota(2, None)));
+        assert_eq!(quota(0, Some(1)), quota(5, None).tighter(quota(0, Some(1))));
+        assert_eq!(
+            quota(0, Some(3)),
+            quota(0, Some(3)).tighter(quota(0, Some(1)))
+        );
+    }
+}
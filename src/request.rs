@@ -1,22 +1,132 @@
-use std::u8;
+use std::num::NonZeroU64;
 
 use http::Method;
 use serde::{Deserialize, Serialize};
 use smol_str::SmolStr;
 
+use crate::ban::BanInfo;
+
+const DEFAULT_PAGE_LIMIT: usize = 100;
+const MAX_PAGE_LIMIT: usize = 1000;
+
+/// `offset` and `limit` query parameters of list endpoints.
+#[derive(Eq, PartialEq, Debug, Clone, Copy)]
+pub(super) struct Page {
+    pub(super) offset: usize,
+    pub(super) limit: usize,
+}
+
+impl Default for Page {
+    fn default() -> Self {
+        Page {
+            offset: 0,
+            limit: DEFAULT_PAGE_LIMIT,
+        }
+    }
+}
+
+impl Page {
+    // unknown parameters are ignored, limit is capped by MAX_PAGE_LIMIT
+    pub(super) fn from_query(query: &str) -> Option<Page> {
+        let mut page = Page::default();
+        for param in query.split('&').filter(|param| !param.is_empty()) {
+            let (name, value) = param.split_once('=').unwrap_or((param, ""));
+            match name {
+                "offset" => page.offset = value.parse().ok()?,
+                "limit" => page.limit = value.parse::<usize>().ok()?.min(MAX_PAGE_LIMIT),
+                _ => continue,
+            }
+        }
+
+        Some(page)
+    }
+
+    pub(super) fn slice<'a, T>(&self, items: &'a [T]) -> &'a [T] {
+        let start = self.offset.min(items.len());
+        let end = start.saturating_add(self.limit).min(items.len());
+        &items[start..end]
+    }
+}
+
 #[derive(Eq, PartialEq, Debug)]
 pub(super) enum Handler {
     Auth,
-    GetUser,
+    Refresh,
     RegisterUser,
+    Jwks,
+    // the rest is served to the holder of an access token
+    Authorized(AuthorizedHandler),
+}
+
+#[derive(Eq, PartialEq, Debug)]
+pub(super) enum AuthorizedHandler {
+    GetUser,
     EditUser,
     BlacklistUser { user: SmolStr },
     UnblacklistUser { user: SmolStr },
     BlacklistSubnet { subnet: SmolStr, mask: u8 },
     UnblacklistSubnet { subnet: SmolStr, mask: u8 },
+    // raw query string, parsed into a Page by the handler
+    ListBlacklistedUsers { query: SmolStr },
+    ListBlacklistedSubnets { query: SmolStr },
+    CheckIp { ip: SmolStr },
+    RotateKeys,
+    Logout,
+    RevokeUserSessions { user: SmolStr },
+}
+
+/// Handler kind without request data, names routes in the config.
+#[derive(Eq, PartialEq, Hash, Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(super) enum Route {
+    Auth,
+    Refresh,
+    GetUser,
+    RegisterUser,
+    EditUser,
+    BlacklistUser,
+    UnblacklistUser,
+    BlacklistSubnet,
+    UnblacklistSubnet,
+    ListBlacklistedUsers,
+    ListBlacklistedSubnets,
+    CheckIp,
+    Jwks,
+    RotateKeys,
+    Logout,
+    RevokeUserSessions,
+}
+
+impl AuthorizedHandler {
+    fn route(&self) -> Route {
+        match self {
+            AuthorizedHandler::GetUser => Route::GetUser,
+            AuthorizedHandler::EditUser => Route::EditUser,
+            AuthorizedHandler::BlacklistUser { .. } => Route::BlacklistUser,
+            AuthorizedHandler::UnblacklistUser { .. } => Route::UnblacklistUser,
+            AuthorizedHandler::BlacklistSubnet { .. } => Route::BlacklistSubnet,
+            AuthorizedHandler::UnblacklistSubnet { .. } => Route::UnblacklistSubnet,
+            AuthorizedHandler::ListBlacklistedUsers { .. } => Route::ListBlacklistedUsers,
+            AuthorizedHandler::ListBlacklistedSubnets { .. } => Route::ListBlacklistedSubnets,
+            AuthorizedHandler::CheckIp { .. } => Route::CheckIp,
+            AuthorizedHandler::RotateKeys => Route::RotateKeys,
+            AuthorizedHandler::Logout => Route::Logout,
+            AuthorizedHandler::RevokeUserSessions { .. } => Route::RevokeUserSessions,
+        }
+    }
 }
 
 impl Handler {
+    pub(super) fn route(&self) -> Route {
+        match self {
+            Handler::Auth => Route::Auth,
+            Handler::Refresh => Route::Refresh,
+            Handler::RegisterUser => Route::RegisterUser,
+            Handler::Jwks => Route::Jwks,
+            Handler::Authorized(handler) => handler.route(),
+        }
+    }
+
     pub(super) fn new(method: &Method, url: &str) -> Option<Handler> {
         let mut splitted = url.split('/');
         splitted.next();
@@ -28,75 +138,135 @@ impl Handler {
                     return None;
                 }
 
-                if !splitted.next().is_none() {
+                let handler = match splitted.next() {
+                    None => Handler::Auth,
+                    Some("refresh") => Handler::Refresh,
+                    Some(_) => return None,
+                };
+
+                if splitted.next().is_some() {
                     return None;
                 }
 
-                return Some(Handler::Auth);
+                return Some(handler);
             }
             "user" => {
-                if !splitted.next().is_none() {
+                if splitted.next().is_some() {
                     return None;
                 }
 
-                match method {
-                    &Method::PUT => {
-                        return Some(Handler::RegisterUser);
-                    }
+                match *method {
+                    Method::PUT => return Some(Handler::RegisterUser),
+                    Method::GET => return Some(Handler::Authorized(AuthorizedHandler::GetUser)),
+                    Method::PATCH => return Some(Handler::Authorized(AuthorizedHandler::EditUser)),
+                    _ => return None,
+                }
+            }
+            ".well-known" => {
+                if method != Method::GET {
+                    return None;
+                }
 
-                    &Method::GET => {
-                        return Some(Handler::GetUser);
-                    }
+                if splitted.next()? != "jwks.json" || splitted.next().is_some() {
+                    return None;
+                }
 
-                    &Method::PATCH => {
-                        return Some(Handler::EditUser);
-                    }
+                return Some(Handler::Jwks);
+            }
+            "logout" => {
+                if method != Method::POST {
+                    return None;
+                }
 
+                if splitted.next().is_some() {
+                    return None;
+                }
+
+                return Some(Handler::Authorized(AuthorizedHandler::Logout));
+            }
+            "admin" => {
+                let handler = match (method, splitted.next()?) {
+                    (&Method::POST, "keys") => {
+                        if splitted.next()? != "rotate" {
+                            return None;
+                        }
+                        AuthorizedHandler::RotateKeys
+                    }
+                    (&Method::DELETE, "sessions") => AuthorizedHandler::RevokeUserSessions {
+                        user: splitted.next()?.into(),
+                    },
                     _ => return None,
+                };
+
+                if splitted.next().is_some() {
+                    return None;
                 }
+
+                return Some(Handler::Authorized(handler));
             }
             "blacklist" => {}
             _ => return None,
         };
 
+        // only list routes take a query
         let second_part = splitted.next()?;
-        match second_part {
+        let (second_part, query) = match second_part.split_once('?') {
+            Some((list @ ("users" | "subnets"), query)) => (list, query),
+            Some(_) => return None,
+            None => (second_part, ""),
+        };
+        let handler = match second_part {
             "subnet" => {
                 let ip: SmolStr = splitted.next()?.into();
                 let mask: u8 = splitted.next()?.parse().ok()?;
 
-                if !splitted.next().is_none() {
+                if splitted.next().is_some() {
                     return None;
                 }
 
-                match method {
-                    &Method::PUT => {
-                        return Some(Handler::BlacklistSubnet { subnet: ip, mask });
-                    }
-                    &Method::DELETE => {
-                        return Some(Handler::UnblacklistSubnet { subnet: ip, mask });
+                match *method {
+                    Method::PUT => Some(AuthorizedHandler::BlacklistSubnet { subnet: ip, mask }),
+                    Method::DELETE => {
+                        Some(AuthorizedHandler::UnblacklistSubnet { subnet: ip, mask })
                     }
-                    _ => return None,
-                };
+                    _ => None,
+                }
             }
             "user" => {
                 let user: SmolStr = splitted.next()?.into();
-                if !splitted.next().is_none() {
+                if splitted.next().is_some() {
                     return None;
                 }
 
-                match method {
-                    &Method::PUT => {
-                        return Some(Handler::BlacklistUser { user });
-                    }
-                    &Method::DELETE => {
-                        return Some(Handler::UnblacklistUser { user });
-                    }
-                    _ => return None,
-                };
+                match *method {
+                    Method::PUT => Some(AuthorizedHandler::BlacklistUser { user }),
+                    Method::DELETE => Some(AuthorizedHandler::UnblacklistUser { user }),
+                    _ => None,
+                }
             }
-            _ => return None,
-        }
+            "users" | "subnets" => {
+                if method != Method::GET || splitted.next().is_some() {
+                    return None;
+                }
+
+                let query = query.into();
+                match second_part {
+                    "users" => Some(AuthorizedHandler::ListBlacklistedUsers { query }),
+                    _ => Some(AuthorizedHandler::ListBlacklistedSubnets { query }),
+                }
+            }
+            "ip" => {
+                let ip: SmolStr = splitted.next()?.into();
+                if method != Method::GET || splitted.next().is_some() {
+                    return None;
+                }
+
+                Some(AuthorizedHandler::CheckIp { ip })
+            }
+            _ => None,
+        };
+
+        handler.map(Handler::Authorized)
     }
 }
 
@@ -107,6 +277,11 @@ pub(super) struct AuthRequest<'body_lf> {
     pub(super) nonce: &'body_lf str,
 }
 
+#[derive(Serialize, Deserialize)]
+pub(super) struct RefreshRequest<'body_lf> {
+    pub(super) refresh_token: &'body_lf str,
+}
+
 #[derive(Serialize, Deserialize)]
 pub(super) struct RegisterUserRequest<'body_lf> {
     pub(super) login: &'body_lf str,
@@ -125,52 +300,119 @@ pub(super) struct EditUserRequest<'body_lf> {
     pub(super) country: Option<SmolStr>,
 }
 
+#[derive(Serialize, Deserialize, Default)]
+pub(super) struct BanRequest {
+    // the ban is permanent if omitted
+    pub(super) expires_in_secs: Option<NonZeroU64>,
+    pub(super) reason: Option<SmolStr>,
+}
+
+impl BanRequest {
+    pub(super) fn into_ban(self, admin: SmolStr, now: u64) -> BanInfo {
+        BanInfo {
+            expires_at: self
+                .expires_in_secs
+                .map(|secs| now.saturating_add(secs.get())),
+            reason: self.reason,
+            admin: Some(admin),
+        }
+    }
+}
+
 #[cfg(test)]
 mod test {
     use http::Method;
 
-    use crate::request::Handler;
+    use crate::request::{AuthorizedHandler, Handler, Page};
 
     #[test]
     fn test_url() {
         assert_eq!(Some(Handler::Auth), Handler::new(&Method::POST, "/auth"));
-        assert_eq!(Some(Handler::GetUser), Handler::new(&Method::GET, "/user"));
+        assert_eq!(
+            Some(Handler::Refresh),
+            Handler::new(&Method::POST, "/auth/refresh")
+        );
+        assert_eq!(
+            Some(Handler::Authorized(AuthorizedHandler::GetUser)),
+            Handler::new(&Method::GET, "/user")
+        );
         assert_eq!(
             Some(Handler::RegisterUser),
             Handler::new(&Method::PUT, "/user")
         );
         assert_eq!(
-            Some(Handler::EditUser),
+            Some(Handler::Authorized(AuthorizedHandler::EditUser)),
             Handler::new(&Method::PATCH, "/user")
         );
         assert_eq!(
-            Some(Handler::BlacklistUser {
+            Some(Handler::Authorized(AuthorizedHandler::BlacklistUser {
                 user: "abcde".into()
-            }),
+            })),
             Handler::new(&Method::PUT, "/blacklist/user/abcde")
         );
         assert_eq!(
-            Some(Handler::UnblacklistUser {
+            Some(Handler::Authorized(AuthorizedHandler::UnblacklistUser {
                 user: "abcde".into()
-            }),
+            })),
             Handler::new(&Method::DELETE, "/blacklist/user/abcde")
         );
         assert_eq!(
-            Some(Handler::BlacklistSubnet {
+            Some(Handler::Authorized(AuthorizedHandler::BlacklistSubnet {
                 subnet: "65.64.5.6".into(),
                 mask: 11
-            }),
+            })),
             Handler::new(&Method::PUT, "/blacklist/subnet/65.64.5.6/11")
         );
         assert_eq!(
-            Some(Handler::UnblacklistSubnet {
+            Some(Handler::Authorized(AuthorizedHandler::UnblacklistSubnet {
                 subnet: "65.64.5.6".into(),
                 mask: 11
-            }),
+            })),
             Handler::new(&Method::DELETE, "/blacklist/subnet/65.64.5.6/11")
         );
+        assert_eq!(
+            Some(Handler::Jwks),
+            Handler::new(&Method::GET, "/.well-known/jwks.json")
+        );
+        assert_eq!(
+            Some(Handler::Authorized(AuthorizedHandler::RotateKeys)),
+            Handler::new(&Method::POST, "/admin/keys/rotate")
+        );
+        assert_eq!(
+            Some(Handler::Authorized(AuthorizedHandler::Logout)),
+            Handler::new(&Method::POST, "/logout")
+        );
+        assert_eq!(
+            Some(Handler::Authorized(AuthorizedHandler::RevokeUserSessions {
+                user: "abcde".into()
+            })),
+            Handler::new(&Method::DELETE, "/admin/sessions/abcde")
+        );
+
+        assert_eq!(
+            Some(Handler::Authorized(
+                AuthorizedHandler::ListBlacklistedUsers { query: "".into() }
+            )),
+            Handler::new(&Method::GET, "/blacklist/users")
+        );
+        assert_eq!(
+            Some(Handler::Authorized(
+                AuthorizedHandler::ListBlacklistedSubnets {
+                    query: "offset=20&limit=many".into()
+                }
+            )),
+            Handler::new(&Method::GET, "/blacklist/subnets?offset=20&limit=many")
+        );
+        assert_eq!(
+            Some(Handler::Authorized(AuthorizedHandler::CheckIp {
+                ip: "2001:db8::1".into()
+            })),
+            Handler::new(&Method::GET, "/blacklist/ip/2001:db8::1")
+        );
 
         assert_eq!(None, Handler::new(&Method::POST, "/auth/"));
+        assert_eq!(None, Handler::new(&Method::POST, "/auth/refresh/"));
+        assert_eq!(None, Handler::new(&Method::GET, "/auth/refresh"));
         assert_eq!(None, Handler::new(&Method::GET, "/user/"));
         assert_eq!(None, Handler::new(&Method::PUT, "/user/"));
         assert_eq!(None, Handler::new(&Method::PATCH, "/user/"));
@@ -187,5 +429,43 @@ mod test {
             None,
             Handler::new(&Method::DELETE, "/blacklist/subnet/65.64.5.6/11/")
         );
+        assert_eq!(None, Handler::new(&Method::POST, "/.well-known/jwks.json"));
+        assert_eq!(None, Handler::new(&Method::GET, "/.well-known/jwks.json/"));
+        assert_eq!(None, Handler::new(&Method::GET, "/admin/keys/rotate"));
+        assert_eq!(None, Handler::new(&Method::POST, "/admin/keys/rotate/"));
+        assert_eq!(None, Handler::new(&Method::GET, "/logout"));
+        assert_eq!(None, Handler::new(&Method::DELETE, "/admin/sessions"));
+        assert_eq!(
+            None,
+            Handler::new(&Method::DELETE, "/admin/sessions/abcde/")
+        );
+        assert_eq!(None, Handler::new(&Method::PUT, "/blacklist/users"));
+        assert_eq!(None, Handler::new(&Method::GET, "/blacklist/users/"));
+        // other routes don't take a query
+        assert_eq!(None, Handler::new(&Method::GET, "/user?x=1"));
+        assert_eq!(None, Handler::new(&Method::GET, "/blacklist/ip?1.2.3.4"));
+        assert_eq!(None, Handler::new(&Method::GET, "/blacklist/ip"));
+        assert_eq!(None, Handler::new(&Method::DELETE, "/blacklist/ip/1.2.3.4"));
+    }
+
+    #[test]
+    fn test_page_query() {
+        let page = |offset, limit| Some(Page { offset, limit });
+        assert_eq!(Some(Page::default()), Page::from_query(""));
+        assert_eq!(page(20, 1000), Page::from_query("offset=20&limit=5000"));
+        // unknown parameters are ignored, with or without a value
+        assert_eq!(page(0, 5), Page::from_query("limit=5&pretty&x=1"));
+        assert_eq!(None, Page::from_query("limit=many"));
+        assert_eq!(None, Page::from_query("offset"));
+    }
+
+    #[test]
+    fn test_page() {
+        let items = [1, 2, 3, 4, 5];
+        let page = |offset, limit| Page { offset, limit }.slice(&items).to_vec();
+        assert_eq!(vec![1, 2], page(0, 2));
+        assert_eq!(vec![4, 5], page(3, 10));
+        assert!(page(5, 10).is_empty());
+        assert!(page(usize::MAX, usize::MAX).is_empty());
     }
 }
@@ -0,0 +1,119 @@
+use std::{fmt::Display, io::Write};
+
+use http::StatusCode;
+
+const SERVER: &[u8] = b"Server: Huyak-huyak\r\n";
+
+// statuses the server sends, others are formatted on the fly
+fn status_line(status: StatusCode) -> Option<&'static str> {
+    let line = match status.as_u16() {
+        100 => "HTTP/1.1 100 Continue\r\n",
+        200 => "HTTP/1.1 200 OK\r\n",
+        201 => "HTTP/1.1 201 Created\r\n",
+        202 => "HTTP/1.1 202 Accepted\r\n",
+        204 => "HTTP/1.1 204 No Content\r\n",
+        400 => "HTTP/1.1 400 Bad Request\r\n",
+        401 => "HTTP/1.1 401 Unauthorized\r\n",
+        403 => "HTTP/1.1 403 Forbidden\r\n",
+        404 => "HTTP/1.1 404 Not Found\r\n",
+        408 => "HTTP/1.1 408 Request Timeout\r\n",
+        409 => "HTTP/1.1 409 Conflict\r\n",
+        413 => "HTTP/1.1 413 Payload Too Large\r\n",
+        417 => "HTTP/1.1 417 Expectation Failed\r\n",
+        429 => "HTTP/1.1 429 Too Many Requests\r\n",
+        431 => "HTTP/1.1 431 Request Header Fields Too Large\r\n",
+        500 => "HTTP/1.1 500 Internal Server Error\r\n",
+        501 => "HTTP/1.1 501 Not Implemented\r\n",
+        503 => "HTTP/1.1 503 Service Unavailable\r\n",
+        _ => return None,
+    };
+
+    Some(line)
+}
+
+/// Response written straight into the output buffer of a connection,
+/// the status line first, then headers, then the body with its length.
+///
+/// The buffer is reused between responses, so nothing is allocated
+/// once it has grown to the size of the usual batch of responses.
+pub struct Response<'a> {
+    out: &'a mut Vec<u8>,
+}
+
+impl<'a> Response<'a> {
+    pub fn new(out: &'a mut Vec<u8>, status: StatusCode, keep_alive: bool) -> Response<'a> {
+        match status_line(status) {
+            Some(line) => out.extend_from_slice(line.as_bytes()),
+            None => {
+                let reason = status.canonical_reason().unwrap_or("Unknown");
+                let _ = write!(out, "HTTP/1.1 {} {reason}\r\n", status.as_u16());
+            }
+        }
+        out.extend_from_slice(SERVER);
+        if keep_alive {
+            out.extend_from_slice(b"Connection: keep-alive\r\n");
+        } else {
+            out.extend_from_slice(b"Connection: close\r\n");
+        }
+
+        Response { out }
+    }
+
+    pub fn header(self, name: &str, value: impl Display) -> Response<'a> {
+        let _ = write!(self.out, "{name}: {value}\r\n");
+        self
+    }
+
+    /// Header lines formatted beforehand, every one ends with \r\n.
+    pub fn raw_headers(self, lines: &str) -> Response<'a> {
+        self.out.extend_from_slice(lines.as_bytes());
+        self
+    }
+
+    pub fn empty(self) {
+        self.out.extend_from_slice(b"Content-Length: 0\r\n\r\n");
+    }
+
+    /// The body is the concatenation of `parts`.
+    pub fn body(self, content_type: &str, parts: &[&[u8]]) {
+        let len: usize = parts.iter().map(|part| part.len()).sum();
+        let _ = write!(
+            self.out,
+            "Content-Type: {content_type}\r\nContent-Length: {len}\r\n\r\n"
+        );
+        for part in parts {
+            self.out.extend_from_slice(part);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use http::StatusCode;
+
+    use crate::response::Response;
+
+    #[test]
+    fn test_response() {
+        let mut out = Vec::new();
+        Response::new(&mut out, StatusCode::OK, true)
+            .header("X-Refresh-Token", "abc")
+            .body("application/json", &[b"\"", b"token", b"\""]);
+        Response::new(&mut out, StatusCode::NOT_FOUND, false)
+            .raw_headers("X-RateLimit-Limit: 5\r\n")
+            .empty();
+
+        let expected = "HTTP/1.1 200 OK\r\nServer: Huyak-huyak\r\nConnection: keep-alive\r\n\
+            X-Refresh-Token: abc\r\nContent-Type: application/json\r\nContent-Length: 7\r\n\r\n\"token\"\
+            HTTP/1.1 404 Not Found\r\nServer: Huyak-huyak\r\nConnection: close\r\n\
+            X-RateLimit-Limit: 5\r\nContent-Length: 0\r\n\r\n";
+        assert_eq!(expected, String::from_utf8(out).unwrap());
+    }
+
+    #[test]
+    fn test_status_line() {
+        let mut out = Vec::new();
+        Response::new(&mut out, StatusCode::IM_A_TEAPOT, true).empty();
+        assert!(out.starts_with(b"HTTP/1.1 418 I'm a teapot\r\n"));
+    }
+}
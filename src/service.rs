@@ -1,83 +1,409 @@
-use std::{net::Ipv4Addr, str::FromStr, sync::Arc};
+use std::{
+    fmt::Write,
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+    sync::Arc,
+    time::Duration,
+};
 
 use arrayvec::ArrayString;
 use http::{Method, StatusCode};
-use ipnet::Ipv4Net;
-use jwt_simple::token::MAX_HEADER_LENGTH;
+use ipnet::IpNet;
 use monoio::{
+    buf::{IoBufMut, SliceMut},
     io::{AsyncReadRent, AsyncWriteRentExt},
     net::TcpStream,
+    time::Instant,
 };
 
 use crate::{
-    request::{AuthRequest, EditUserRequest, Handler, RegisterUserRequest},
-    state::State,
+    ban::unix_now,
+    chunked::{ChunkedDecoder, ChunkedError},
+    config::Limits,
+    forwarded::TrustedProxies,
+    keys::SigningKey,
+    rate_limit::{Client, Quota},
+    request::{
+        AuthRequest, AuthorizedHandler, BanRequest, EditUserRequest, Handler, Page, RefreshRequest,
+        RegisterUserRequest,
+    },
+    response::Response,
+    session::RefreshError,
+    shutdown,
+    state::{AccessError, AuthError, Identity, State, TokenError, Tokens},
 };
 
 const INIT_READ_SIZE: usize = 4096 * 4;
+// free space a read gets at least, the buffer grows otherwise
+const MIN_READ_SIZE: usize = 4096;
+// responses kept back while there are pipelined requests to answer
+const MAX_PENDING_OUTPUT: usize = 64 * 1024;
 const MAX_HEADERS: usize = 256;
-const MAX_TOKEN_LEN: usize = 256;
-const MAX_IP_LEN: usize = 16;
+// RS256 signature alone takes 342 chars, session claims add ~100 more
+const MAX_TOKEN_LEN: usize = 2048;
+// the whole chain of X-Forwarded-For or Forwarded hops
+const MAX_FORWARDED_LEN: usize = 1024;
+const PROBLEM_JSON: &str = "application/problem+json";
+
+/// Why a request is refused. Clients accepting `application/problem+json`
+/// get the code and the message in the body, others get the bare status.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CPError {
+    BadRequest,
+    AmbiguousBodyLength,
+    UnknownClientIp,
+    IpBanned,
+    InvalidCredentials,
+    InvalidRefreshToken,
+    MissingToken,
+    InvalidToken,
+    TokenExpired,
+    UnknownUser,
+    UserBanned,
+    WrongCountry,
+    AdminRequired,
+    NotFound,
+    UserNotFound,
+    UserExists,
+    AlreadyBanned,
+    UserNotBanned,
+    SubnetNotBanned,
+    KeyExists,
+    KeyGenerationFailed,
+    RateLimited,
+    LoginThrottled,
+    PayloadTooLarge,
+    HeadersTooLarge,
+    UnsupportedTransferEncoding,
+    ExpectationFailed,
+    RequestTimeout,
+}
 
-const MAX_HEADER_SIZE: usize = 10 * 1024;
-const MAX_BODY_SIZE: usize = 100 * 1024;
+impl CPError {
+    // the bare status, or the details for clients which asked for them
+    fn write(
+        self,
+        out: &mut Vec<u8>,
+        keep_alive: bool,
+        extra_headers: &str,
+        problem_details: bool,
+    ) {
+        let (code, error_code, message) = self.details();
+        let mut response = Response::new(out, code, keep_alive).raw_headers(extra_headers);
+        // RFC 6750 3.1, tells clients to refresh the token without parsing the body
+        if self == CPError::TokenExpired {
+            response = response.header(
+                "WWW-Authenticate",
+                r#"Bearer error="invalid_token", error_description="the access token has expired""#,
+            );
+        }
+        if !problem_details {
+            response.empty();
+            return;
+        }
 
-#[derive(Debug)]
-pub enum CPError {}
+        let body = serde_json::json!({
+            "status": code.as_u16(),
+            "code": error_code,
+            "message": message,
+        })
+        .to_string();
+        response.body(PROBLEM_JSON, &[body.as_bytes()]);
+    }
+
+    // status, machine-readable code and message
+    fn details(self) -> (StatusCode, &'static str, &'static str) {
+        use CPError::*;
+
+        match self {
+            BadRequest => (
+                StatusCode::BAD_REQUEST,
+                "bad_request",
+                "the request is malformed",
+            ),
+            AmbiguousBodyLength => (
+                StatusCode::BAD_REQUEST,
+                "ambiguous_body_length",
+                "the body length is invalid or given by both Content-Length and Transfer-Encoding",
+            ),
+            UnknownClientIp => (
+                StatusCode::FORBIDDEN,
+                "unknown_client_ip",
+                "the client address can't be told from the forwarding headers",
+            ),
+            IpBanned => (
+                StatusCode::FORBIDDEN,
+                "ip_banned",
+                "the client IP is banned",
+            ),
+            InvalidCredentials => (
+                StatusCode::FORBIDDEN,
+                "invalid_credentials",
+                "wrong login or password",
+            ),
+            InvalidRefreshToken => (
+                StatusCode::FORBIDDEN,
+                "invalid_refresh_token",
+                "the refresh token is invalid, expired or used already",
+            ),
+            MissingToken => (
+                StatusCode::FORBIDDEN,
+                "missing_token",
+                "the X-Api-Key header is required",
+            ),
+            InvalidToken => (
+                StatusCode::FORBIDDEN,
+                "invalid_token",
+                "the access token is invalid or revoked",
+            ),
+            TokenExpired => (
+                StatusCode::UNAUTHORIZED,
+                "token_expired",
+                "the access token has expired",
+            ),
+            UnknownUser => (
+                StatusCode::FORBIDDEN,
+                "unknown_user",
+                "the user of the token doesn't exist",
+            ),
+            UserBanned => (StatusCode::FORBIDDEN, "user_banned", "the user is banned"),
+            WrongCountry => (
+                StatusCode::FORBIDDEN,
+                "wrong_country",
+                "the client IP is outside the country of the user",
+            ),
+            AdminRequired => (
+                StatusCode::FORBIDDEN,
+                "admin_required",
+                "only admins from their own country may do this",
+            ),
+            NotFound => (StatusCode::NOT_FOUND, "not_found", "no such endpoint"),
+            UserNotFound => (StatusCode::NOT_FOUND, "user_not_found", "no such user"),
+            UserExists => (StatusCode::CONFLICT, "user_exists", "the login is taken"),
+            AlreadyBanned => (
+                StatusCode::CONFLICT,
+                "already_banned",
+                "the ban is in place already",
+            ),
+            UserNotBanned => (StatusCode::NOT_FOUND, "not_banned", "the user isn't banned"),
+            SubnetNotBanned => (
+                StatusCode::CONFLICT,
+                "not_banned",
+                "the subnet isn't banned",
+            ),
+            KeyExists => (
+                StatusCode::CONFLICT,
+                "key_exists",
+                "the key is in the key ring already",
+            ),
+            KeyGenerationFailed => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "key_generation_failed",
+                "a new signing key can't be generated",
+            ),
+            RateLimited => (
+                StatusCode::TOO_MANY_REQUESTS,
+                "rate_limited",
+                "too many requests, retry after the time in Retry-After",
+            ),
+            LoginThrottled => (
+                StatusCode::TOO_MANY_REQUESTS,
+                "login_throttled",
+                "too many failed logins, retry after the time in Retry-After",
+            ),
+            PayloadTooLarge => (
+                StatusCode::PAYLOAD_TOO_LARGE,
+                "payload_too_large",
+                "the body exceeds the size limit",
+            ),
+            HeadersTooLarge => (
+                StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE,
+                "headers_too_large",
+                "the headers exceed the size limit",
+            ),
+            UnsupportedTransferEncoding => (
+                StatusCode::NOT_IMPLEMENTED,
+                "unsupported_transfer_encoding",
+                "only the chunked transfer coding is supported",
+            ),
+            ExpectationFailed => (
+                StatusCode::EXPECTATION_FAILED,
+                "expectation_failed",
+                "only Expect: 100-continue is supported",
+            ),
+            RequestTimeout => (
+                StatusCode::REQUEST_TIMEOUT,
+                "request_timeout",
+                "the request didn't arrive in time",
+            ),
+        }
+    }
+}
+
+// opted in by listing application/problem+json in Accept with a nonzero q
+fn accepts_problem_json(value: &[u8]) -> bool {
+    let Ok(value) = std::str::from_utf8(value) else {
+        return false;
+    };
+
+    value.split(',').any(|range| {
+        let mut params = range.split(';');
+        let media = params.next().unwrap_or_default().trim();
+        if !media.eq_ignore_ascii_case(PROBLEM_JSON) {
+            return false;
+        }
+
+        let q = params
+            .filter_map(|param| param.split_once('='))
+            .find(|(name, _)| name.trim().eq_ignore_ascii_case("q"));
+        match q {
+            Some((_, q)) => q.trim().parse::<f32>().is_ok_and(|q| q > 0.0),
+            None => true,
+        }
+    })
+}
 
 pub struct ConnectionProcessor {
     state: Arc<State>,
     stream: TcpStream,
+    peer: SocketAddr,
+    limits: Limits,
+    trusted_proxies: Arc<TrustedProxies>,
+    // added to the next response, every line ends with \r\n
+    extra_headers: String,
+    // the client asked for error details in application/problem+json
+    problem_details: bool,
+    // the connection stays open after the response
+    keep_alive: bool,
+    // responses not written yet, pipelined ones go out in one write
+    out: Vec<u8>,
 }
 
 impl ConnectionProcessor {
-    pub fn new(state: Arc<State>, stream: TcpStream) -> ConnectionProcessor {
-        ConnectionProcessor { state, stream }
+    pub fn new(
+        state: Arc<State>,
+        stream: TcpStream,
+        peer: SocketAddr,
+        limits: Limits,
+        trusted_proxies: Arc<TrustedProxies>,
+    ) -> ConnectionProcessor {
+        ConnectionProcessor {
+            state,
+            stream,
+            peer,
+            limits,
+            trusted_proxies,
+            extra_headers: String::new(),
+            problem_details: false,
+            keep_alive: true,
+            out: Vec::with_capacity(INIT_READ_SIZE),
+        }
     }
 
     pub async fn process(&mut self) -> Result<(), CPError> {
+        let res = self.serve_requests().await;
+        // whatever made the connection close, the answers so far are due;
+        // a client not reading them doesn't hold the connection either
+        match monoio::time::timeout(self.limits.write_timeout(), self.flush()).await {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => eprintln!("error : {e}, while writing responses"),
+            Err(_) => eprintln!("timeout while writing responses"),
+        }
+        res
+    }
+
+    async fn serve_requests(&mut self) -> Result<(), CPError> {
         use httparse::Status as ParseStatus;
 
         let mut buf: Vec<u8> = Vec::with_capacity(INIT_READ_SIZE);
         let mut res;
+        // bytes of the previous request, the rest of `buf` is the next one
+        let mut consumed = 0;
+        let mut requests = 0;
 
-        let mut ip: Option<ArrayString<MAX_IP_LEN>>;
+        let mut forwarded_chain = ArrayString::<MAX_FORWARDED_LEN>::new();
+        let mut bad_forwarding;
         let mut token: Option<ArrayString<MAX_TOKEN_LEN>>;
         let mut content_length: Option<usize>;
+        let mut bad_content_length;
+        // Some(false) for codings other than a single chunked
+        let mut chunked: Option<bool>;
+        let mut expect_continue;
+        let mut bad_expectation;
+        let mut connection_close;
+        let mut connection_keep_alive;
         let mut handler;
 
         loop {
-            buf.clear();
-            ip = None;
+            // the previous response is sent, nothing is lost by closing here
+            if !self.keep_alive || shutdown::is_requested() {
+                return Ok(());
+            }
+
+            buf.drain(..consumed);
+            self.extra_headers.clear();
+            self.problem_details = false;
+            forwarded_chain.clear();
+            bad_forwarding = false;
             token = None;
             content_length = None;
+            bad_content_length = false;
+            chunked = None;
+            expect_continue = false;
+            bad_expectation = false;
+            connection_close = false;
+            connection_keep_alive = false;
             handler = None;
 
             // parsing http-header
             //
             let mut header_len = 0;
+            // a pipelined request may be buffered already
+            let mut need_read = buf.is_empty();
+            // the headers have to arrive in time once the request has started
+            let mut header_deadline = None;
             while header_len == 0 {
-                (res, buf) = self.stream.read(buf).await;
-                let Ok(_sz) = res else {
-                    return Ok(());
-                };
+                if need_read {
+                    // an idle connection is closed after the keep-alive timeout, right away on shutdown
+                    (res, buf) = if buf.is_empty() {
+                        // only the read is raced, an interrupted write would lose answers
+                        if self.flush_in_time().await.is_err() {
+                            return Ok(());
+                        }
+                        let idle_timeout = self.limits.keep_alive_timeout();
+                        monoio::select! {
+                            read = self.read_more(buf) => read,
+                            _ = monoio::time::sleep(idle_timeout) => return Ok(()),
+                            _ = shutdown::wait() => return Ok(()),
+                        }
+                    } else {
+                        let deadline = *header_deadline
+                            .get_or_insert_with(|| Instant::now() + self.limits.header_timeout());
+                        let Some(read) = self.read_until(buf, deadline).await else {
+                            self.reject(CPError::RequestTimeout).await.unwrap();
+                            return Ok(());
+                        };
+                        read
+                    };
+                    let Ok(_sz) = res else {
+                        return Ok(());
+                    };
 
-                if _sz == 0 {
-                    return Ok(());
+                    if _sz == 0 {
+                        return Ok(());
+                    }
                 }
-
-                // if buf.len() > MAX_HEADER_SIZE {
-                //     self.write_code(StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE)
-                //         .await
-                //         .unwrap();
-                //     return Ok(());
-                // }
+                need_read = true;
 
                 // lightweight parsing http body
                 let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
                 let mut req = httparse::Request::new(&mut headers);
                 let res = match req.parse(buf.as_slice()) {
                     Ok(res) => res,
+                    Err(httparse::Error::TooManyHeaders) => {
+                        self.reject(CPError::HeadersTooLarge).await.unwrap();
+                        return Ok(());
+                    }
                     Err(err) => {
                         eprintln!("error parse buffer: {err}");
                         return Ok(());
@@ -85,6 +411,12 @@ impl ConnectionProcessor {
                 };
 
                 header_len = match res {
+                    // everything buffered belongs to the unfinished headers,
+                    // they must not grow past the limit while arriving
+                    ParseStatus::Partial if buf.len() > self.limits.max_header_size => {
+                        self.reject(CPError::HeadersTooLarge).await.unwrap();
+                        return Ok(());
+                    }
                     ParseStatus::Partial => continue,
                     ParseStatus::Complete(compl) => compl,
                 };
@@ -93,10 +425,8 @@ impl ConnectionProcessor {
 
                 handler = Handler::new(&method, req.path.unwrap_or_default());
 
-                if header_len > MAX_HEADER_LENGTH {
-                    self.write_code(StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE)
-                        .await
-                        .unwrap();
+                if header_len > self.limits.max_header_size {
+                    self.reject(CPError::HeadersTooLarge).await.unwrap();
 
                     return Ok(());
                 }
@@ -108,18 +438,51 @@ impl ConnectionProcessor {
                     match name {
                         "content-length" => {
                             let s = unsafe { std::str::from_utf8_unchecked(header.value) };
-                            let Ok(cl) = s.parse::<usize>() else {
-                                continue;
-                            };
-                            content_length = Some(cl);
+                            match s.parse::<usize>() {
+                                // repeated headers have to agree
+                                Ok(cl) if content_length.unwrap_or(cl) == cl => {
+                                    content_length = Some(cl)
+                                }
+                                _ => bad_content_length = true,
+                            }
                         }
-                        "x-forwarded-for" => {
-                            let ip_str = unsafe { std::str::from_utf8_unchecked(header.value) };
-                            let Ok(tmp_ip) = ArrayString::from(ip_str) else {
-                                eprintln!("unable to cast ip {ip_str} into ArrayString");
+                        "transfer-encoding" => {
+                            // the codings are listed in one or more headers
+                            let is_chunked = chunked.is_none()
+                                && std::str::from_utf8(header.value).is_ok_and(|value| {
+                                    value.trim().eq_ignore_ascii_case("chunked")
+                                });
+                            chunked = Some(is_chunked);
+                        }
+                        "expect" => {
+                            if header.value.eq_ignore_ascii_case(b"100-continue") {
+                                expect_continue = true;
+                            } else {
+                                bad_expectation = true;
+                            }
+                        }
+                        name if self.trusted_proxies.reads(name) => {
+                            let Ok(value) = std::str::from_utf8(header.value) else {
+                                bad_forwarding = true;
                                 continue;
                             };
-                            ip = Some(tmp_ip);
+                            // repeated headers make one list
+                            let separated = forwarded_chain.is_empty()
+                                || forwarded_chain.try_push_str(", ").is_ok();
+                            if !separated || forwarded_chain.try_push_str(value).is_err() {
+                                eprintln!("forwarding chain {value} is too long");
+                                bad_forwarding = true;
+                            }
+                        }
+                        "connection" => {
+                            for option in header.value.split(|&b| b == b',') {
+                                let option = std::str::from_utf8(option).unwrap_or_default().trim();
+                                connection_close |= option.eq_ignore_ascii_case("close");
+                                connection_keep_alive |= option.eq_ignore_ascii_case("keep-alive");
+                            }
+                        }
+                        "accept" => {
+                            self.problem_details |= accepts_problem_json(header.value);
                         }
                         "x-api-key" => {
                             let api_key_str =
@@ -133,227 +496,460 @@ impl ConnectionProcessor {
                         _ => continue,
                     };
                 }
+
+                // HTTP/1.1 keeps the connection unless told otherwise, HTTP/1.0 only if told so
+                self.keep_alive =
+                    !connection_close && (req.version == Some(1) || connection_keep_alive);
+                requests += 1;
+                if requests == self.limits.max_requests_per_connection {
+                    self.keep_alive = false;
+                }
+            }
+
+            // a body framed both ways is read differently by different servers,
+            // which is how requests are smuggled past a proxy
+            if bad_content_length || chunked.is_some() && content_length.is_some() {
+                self.reject(CPError::AmbiguousBodyLength).await.unwrap();
+                return Ok(());
+            }
+            if chunked == Some(false) {
+                self.reject(CPError::UnsupportedTransferEncoding)
+                    .await
+                    .unwrap();
+                return Ok(());
+            }
+            if bad_expectation {
+                self.reject(CPError::ExpectationFailed).await.unwrap();
+                return Ok(());
             }
 
-            let content_length = content_length.unwrap_or(0);
+            // refused before a byte of the body is read
+            if content_length.is_some_and(|cl| cl > self.limits.max_body_size) {
+                self.reject(CPError::PayloadTooLarge).await.unwrap();
+                return Ok(());
+            }
 
-            while buf.len() < content_length + header_len {
-                (res, buf) = self.stream.read(buf).await;
-                if let Err(e) = res {
-                    eprintln!("read http body: {e}");
-                    return Ok(());
+            let has_body = chunked.is_some() || content_length.is_some_and(|cl| cl > 0);
+            // the client waits for a go-ahead before sending the body
+            if expect_continue && has_body && buf.len() == header_len {
+                // goes out with the read of the body
+                self.out.extend_from_slice(b"HTTP/1.1 100 Continue\r\n\r\n");
+            }
+
+            let body_deadline = Instant::now() + self.limits.body_timeout();
+            let body_len = if chunked.is_some() {
+                let mut decoder = ChunkedDecoder::new(self.limits.max_body_size);
+                loop {
+                    match decoder.decode(&mut buf, header_len) {
+                        Ok(Some(len)) => break len,
+                        Ok(None) => {}
+                        Err(ChunkedError::TooLarge) => {
+                            self.reject(CPError::PayloadTooLarge).await.unwrap();
+                            return Ok(());
+                        }
+                        Err(ChunkedError::Invalid) => {
+                            self.reject(CPError::BadRequest).await.unwrap();
+                            return Ok(());
+                        }
+                    }
+
+                    let Some(read) = self.read_until(buf, body_deadline).await else {
+                        self.reject(CPError::RequestTimeout).await.unwrap();
+                        return Ok(());
+                    };
+                    (res, buf) = read;
+                    match res {
+                        Ok(0) => return Ok(()),
+                        Ok(_) => {}
+                        Err(e) => {
+                            eprintln!("read http body: {e}");
+                            return Ok(());
+                        }
+                    }
                 }
+            } else {
+                let content_length = content_length.unwrap_or(0);
 
-                if buf.len() > header_len + MAX_BODY_SIZE {
-                    self.write_code(StatusCode::PAYLOAD_TOO_LARGE)
-                        .await
-                        .unwrap();
-                    return Ok(());
+                while buf.len() < content_length + header_len {
+                    let Some(read) = self.read_until(buf, body_deadline).await else {
+                        self.reject(CPError::RequestTimeout).await.unwrap();
+                        return Ok(());
+                    };
+                    (res, buf) = read;
+                    match res {
+                        Ok(0) => return Ok(()),
+                        Ok(_) => {}
+                        Err(e) => {
+                            eprintln!("read http body: {e}");
+                            return Ok(());
+                        }
+                    }
                 }
-            }
 
-            let body = &buf[header_len..header_len + content_length];
+                content_length
+            };
+
+            consumed = header_len + body_len;
+            let body = &buf[header_len..header_len + body_len];
 
             let handler = match handler {
                 Some(handler) => handler,
                 None => {
-                    self.write_code(StatusCode::NOT_FOUND).await?;
+                    self.write_error(CPError::NotFound).await?;
                     continue;
                 }
             };
 
-            let ip = match ip {
-                Some(ip) => {
-                    let ip = &ip[..];
-
-                    let Ok(ip) = Ipv4Addr::from_str(ip) else {
-                        self.write_code(StatusCode::FORBIDDEN).await.unwrap();
-                        continue;
-                    };
-
-                    ip
-                }
-                None => {
-                    self.write_code(StatusCode::FORBIDDEN).await.unwrap();
-                    continue;
-                }
+            let ip = if bad_forwarding {
+                None
+            } else {
+                self.trusted_proxies
+                    .client_ip(self.peer.ip(), &forwarded_chain)
+            };
+            let Some(ip) = ip else {
+                self.write_error(CPError::UnknownClientIp).await.unwrap();
+                continue;
             };
 
             if self.state.is_ip_banned(ip) {
-                self.write_code(StatusCode::FORBIDDEN).await.unwrap();
+                self.write_error(CPError::IpBanned).await.unwrap();
                 continue;
             }
 
-            if let Handler::Auth = handler {
-                let Ok(request) = serde_json::from_slice::<AuthRequest<'_>>(body) else {
-                    self.write_code(StatusCode::FORBIDDEN).await.unwrap();
-                    // self.write_bad_request().await.unwrap();
+            let route = handler.route();
+            let ip_quota = self.state.rate_limiter.acquire(route, Client::Ip(ip));
+            if let Some(quota) = ip_quota {
+                self.set_rate_limit_headers(&quota);
+                if let Some(retry_after) = quota.retry_after {
+                    self.write_too_many_requests(CPError::RateLimited, retry_after)
+                        .await
+                        .unwrap();
                     continue;
-                };
-
-                match self.state.authenticate(
-                    request.login,
-                    request.password.as_str(),
-                    request.nonce,
-                    ip,
-                ) {
-                    Some(token) => {
-                        self.write_auth_token(StatusCode::OK, token).await.unwrap();
-                    }
-                    None => {
-                        self.write_code(StatusCode::FORBIDDEN).await.unwrap();
-                    }
                 }
-                continue;
             }
 
-            if let Handler::RegisterUser = handler {
-                let Ok(request) = serde_json::from_slice::<RegisterUserRequest<'_>>(body) else {
-                    self.write_bad_request().await.unwrap();
+            let handler = match handler {
+                Handler::Jwks => {
+                    let jwks = self.state.jwks();
+                    self.write_json(StatusCode::OK, &jwks).await.unwrap();
                     continue;
-                };
+                }
+                Handler::Auth => {
+                    let Ok(request) = serde_json::from_slice::<AuthRequest<'_>>(body) else {
+                        self.write_error(CPError::InvalidCredentials).await.unwrap();
+                        // self.write_bad_request().await.unwrap();
+                        continue;
+                    };
 
-                if self.state.is_user_exists(request.login) {
-                    self.write_code(StatusCode::CONFLICT).await.unwrap();
+                    let auth = self.state.authenticate(
+                        request.login,
+                        request.password.as_str(),
+                        request.nonce,
+                        ip,
+                    );
+                    match auth.await {
+                        Ok(tokens) => {
+                            self.write_auth_token(StatusCode::OK, tokens).await.unwrap();
+                        }
+                        Err(AuthError::Invalid) => {
+                            self.write_error(CPError::InvalidCredentials).await.unwrap();
+                        }
+                        Err(AuthError::Throttled { retry_after }) => {
+                            self.write_too_many_requests(
+                                CPError::LoginThrottled,
+                                Duration::from_secs(retry_after),
+                            )
+                            .await
+                            .unwrap();
+                        }
+                    }
+                    continue;
+                }
+                Handler::Refresh => {
+                    let Ok(request) = serde_json::from_slice::<RefreshRequest<'_>>(body) else {
+                        self.write_bad_request().await.unwrap();
+                        continue;
+                    };
+
+                    match self.state.refresh(request.refresh_token, ip) {
+                        Ok(tokens) => {
+                            let answer = serde_json::json!({
+                                "access_token": tokens.access_token,
+                                "refresh_token": tokens.refresh_token,
+                                "token_type": "Bearer",
+                                "expires_in": self.state.access_token_ttl().as_secs(),
+                            })
+                            .to_string();
+                            self.write_json(StatusCode::OK, &answer).await.unwrap();
+                        }
+                        Err(e) => {
+                            if e == RefreshError::Reused {
+                                eprintln!("refresh token reuse detected from {ip}");
+                            }
+                            self.write_error(CPError::InvalidRefreshToken)
+                                .await
+                                .unwrap();
+                        }
+                    }
                     continue;
                 }
+                Handler::RegisterUser => {
+                    let Ok(request) = serde_json::from_slice::<RegisterUserRequest<'_>>(body)
+                    else {
+                        self.write_bad_request().await.unwrap();
+                        continue;
+                    };
+
+                    if self.state.is_user_exists(request.login) {
+                        self.write_error(CPError::UserExists).await.unwrap();
+                        continue;
+                    }
 
-                self.state.create_user(
-                    request.login,
-                    request.password.as_str(),
-                    request.name,
-                    request.phone,
-                    request.country,
-                );
+                    let created = self.state.create_user(
+                        request.login,
+                        request.password.as_str(),
+                        request.name,
+                        request.phone,
+                        request.country,
+                    );
+                    if !created.await {
+                        self.write_error(CPError::UserExists).await.unwrap();
+                        continue;
+                    }
 
-                self.write_code(StatusCode::CREATED).await.unwrap();
+                    self.write_code(StatusCode::CREATED).await.unwrap();
 
-                continue;
-            }
+                    continue;
+                }
+                Handler::Authorized(handler) => handler,
+            };
 
             let Some(tok) = token else {
-                self.write_code(StatusCode::FORBIDDEN).await.unwrap();
+                self.write_error(CPError::MissingToken).await.unwrap();
                 continue;
             };
 
             let token = &tok[..];
 
-            let Some(login) = self.state.get_user_login(token) else {
-                //
-                //[self.write_bad_request().await.unwrap();
-                self.write_code(StatusCode::FORBIDDEN).await.unwrap();
-                continue;
+            let Identity { login, sid } = match self.state.get_user_login(token) {
+                Ok(identity) => identity,
+                Err(TokenError::Expired) => {
+                    self.write_error(CPError::TokenExpired).await.unwrap();
+                    continue;
+                }
+                Err(TokenError::Invalid) => {
+                    //[self.write_bad_request().await.unwrap();
+                    self.write_error(CPError::InvalidToken).await.unwrap();
+                    continue;
+                }
             };
 
-            if let None = self.state.is_proper_country(login.clone(), ip) {
-                self.write_code(StatusCode::FORBIDDEN).await.unwrap();
+            if self.state.is_proper_country(login.clone(), ip).is_none() {
+                self.write_error(CPError::UnknownUser).await.unwrap();
                 continue;
             }
 
-            match handler {
-                Handler::Auth => {
-                    todo!()
+            let login_quota = self
+                .state
+                .rate_limiter
+                .acquire(route, Client::Login(login.clone()));
+            if let Some(mut quota) = login_quota {
+                if let Some(ip_quota) = ip_quota {
+                    quota = quota.tighter(ip_quota);
                 }
-                Handler::GetUser => {
-                    let Some(user_str) = self.state.get_user(login, ip) else {
-                        self.write_code(StatusCode::FORBIDDEN).await.unwrap();
-                        continue;
+                self.set_rate_limit_headers(&quota);
+                if let Some(retry_after) = quota.retry_after {
+                    self.write_too_many_requests(CPError::RateLimited, retry_after)
+                        .await
+                        .unwrap();
+                    continue;
+                }
+            }
+
+            match handler {
+                AuthorizedHandler::GetUser => {
+                    let user_str = match self.state.get_user(login, ip) {
+                        Ok(user_str) => user_str,
+                        Err(e) => {
+                            let error = match e {
+                                AccessError::UnknownUser => CPError::UnknownUser,
+                                AccessError::Banned => CPError::UserBanned,
+                                AccessError::WrongCountry => CPError::WrongCountry,
+                            };
+                            self.write_error(error).await.unwrap();
+                            continue;
+                        }
                     };
 
                     self.write_json(StatusCode::OK, user_str.as_str())
                         .await
                         .unwrap();
                 }
-                Handler::RegisterUser => {
-                    todo!()
+                AuthorizedHandler::Logout => {
+                    self.state.logout(&sid);
+                    self.write_code(StatusCode::NO_CONTENT).await.unwrap();
                 }
+                AuthorizedHandler::RevokeUserSessions { user } => {
+                    if !self.state.is_prop_admin_cred(login.as_str(), ip) {
+                        self.write_error(CPError::AdminRequired).await.unwrap();
+                        continue;
+                    }
 
-                Handler::EditUser => {
+                    if self.state.revoke_user_tokens(&user).is_none() {
+                        self.write_error(CPError::UserNotFound).await.unwrap();
+                        continue;
+                    }
+
+                    self.write_code(StatusCode::NO_CONTENT).await.unwrap();
+                }
+                AuthorizedHandler::RotateKeys => {
+                    if !self.state.is_prop_admin_cred(login.as_str(), ip) {
+                        self.write_error(CPError::AdminRequired).await.unwrap();
+                        continue;
+                    }
+
+                    // private keys never travel over the API, the new one is generated here
+                    if !body.is_empty() {
+                        self.write_bad_request().await.unwrap();
+                        continue;
+                    }
+
+                    let alg = self.state.signing_algorithm();
+                    let key = match SigningKey::generate_off_worker(alg).await {
+                        Ok(key) => key,
+                        Err(e) => {
+                            eprintln!("unable to generate {alg:?} key: {e:?}");
+                            self.write_error(CPError::KeyGenerationFailed)
+                                .await
+                                .unwrap();
+                            continue;
+                        }
+                    };
+
+                    match self.state.rotate_key(key) {
+                        Some(kid) => {
+                            let answer = serde_json::json!({ "kid": kid }).to_string();
+                            self.write_json(StatusCode::CREATED, &answer).await.unwrap();
+                        }
+                        None => {
+                            self.write_error(CPError::KeyExists).await.unwrap();
+                        }
+                    }
+                }
+
+                AuthorizedHandler::EditUser => {
                     let Ok(request) = serde_json::from_slice::<EditUserRequest<'_>>(body) else {
                         self.write_bad_request().await.unwrap();
                         continue;
                     };
 
-                    if let None = self.state.edit_user(
-                        login,
-                        request.name,
-                        request.password,
-                        request.phone,
-                        request.is_admin,
-                        request.country,
-                    ) {
-                        self.write_code(StatusCode::FORBIDDEN).await.unwrap();
+                    if self
+                        .state
+                        .edit_user(
+                            login,
+                            request.name,
+                            request.password,
+                            request.phone,
+                            request.is_admin,
+                            request.country,
+                        )
+                        .await
+                        .is_none()
+                    {
+                        self.write_error(CPError::UserBanned).await.unwrap();
                         continue;
                     }
 
                     self.write_code(StatusCode::ACCEPTED).await.unwrap();
                 }
-                Handler::BlacklistUser { user } => {
+                AuthorizedHandler::BlacklistUser { user } => {
                     if !self.state.is_prop_admin_cred(login.as_str(), ip) {
-                        self.write_code(StatusCode::FORBIDDEN).await.unwrap();
+                        self.write_error(CPError::AdminRequired).await.unwrap();
                         continue;
                     }
 
-                    let Some(is_blacklisted_now) = self.state.ban_user(&user) else {
-                        self.write_code(StatusCode::NOT_FOUND).await.unwrap();
+                    let request = if body.is_empty() {
+                        BanRequest::default()
+                    } else {
+                        let Ok(request) = serde_json::from_slice::<BanRequest>(body) else {
+                            self.write_bad_request().await.unwrap();
+                            continue;
+                        };
+                        request
+                    };
+                    let ban = request.into_ban(login, unix_now());
+
+                    let Some(is_blacklisted_now) = self.state.ban_user(&user, ban) else {
+                        self.write_error(CPError::UserNotFound).await.unwrap();
                         continue;
                     };
 
                     if is_blacklisted_now {
                         self.write_code(StatusCode::CREATED).await.unwrap();
                     } else {
-                        self.write_code(StatusCode::CONFLICT).await.unwrap();
+                        self.write_error(CPError::AlreadyBanned).await.unwrap();
                     }
                 }
-                Handler::UnblacklistUser { user } => {
+                AuthorizedHandler::UnblacklistUser { user } => {
                     if !self.state.is_prop_admin_cred(login.as_str(), ip) {
-                        self.write_code(StatusCode::FORBIDDEN).await.unwrap();
+                        self.write_error(CPError::AdminRequired).await.unwrap();
                         continue;
                     }
 
                     let Some(is_unblacklisted_now) = self.state.unban_user(&user) else {
-                        self.write_code(StatusCode::NOT_FOUND).await.unwrap();
+                        self.write_error(CPError::UserNotFound).await.unwrap();
                         continue;
                     };
 
                     if is_unblacklisted_now {
                         self.write_code(StatusCode::NO_CONTENT).await.unwrap();
                     } else {
-                        self.write_code(StatusCode::NOT_FOUND).await.unwrap();
+                        self.write_error(CPError::UserNotBanned).await.unwrap();
                     }
                 }
-                Handler::BlacklistSubnet { subnet, mask } => {
+                AuthorizedHandler::BlacklistSubnet { subnet, mask } => {
                     if !self.state.is_prop_admin_cred(login.as_str(), ip) {
-                        self.write_code(StatusCode::FORBIDDEN).await.unwrap();
+                        self.write_error(CPError::AdminRequired).await.unwrap();
                         continue;
                     }
 
-                    let Ok(ip) = Ipv4Addr::from_str(subnet.as_str()) else {
+                    let Ok(ip) = IpAddr::from_str(subnet.as_str()) else {
                         self.write_bad_request().await.unwrap();
                         continue;
                     };
-                    let Ok(_subnet) = Ipv4Net::new(ip, mask) else {
+                    let Ok(_subnet) = IpNet::new(ip, mask) else {
                         self.write_bad_request().await.unwrap();
                         continue;
                     };
 
-                    if self.state.ban_subnet(ip, mask) {
+                    let request = if body.is_empty() {
+                        BanRequest::default()
+                    } else {
+                        let Ok(request) = serde_json::from_slice::<BanRequest>(body) else {
+                            self.write_bad_request().await.unwrap();
+                            continue;
+                        };
+                        request
+                    };
+                    let ban = request.into_ban(login, unix_now());
+
+                    if self.state.ban_subnet(ip, mask, ban) {
                         self.write_code(StatusCode::CREATED).await.unwrap();
                     } else {
-                        self.write_code(StatusCode::CONFLICT).await.unwrap();
+                        self.write_error(CPError::AlreadyBanned).await.unwrap();
                     }
                 }
-                Handler::UnblacklistSubnet { subnet, mask } => {
+                AuthorizedHandler::UnblacklistSubnet { subnet, mask } => {
                     if !self.state.is_prop_admin_cred(login.as_str(), ip) {
-                        self.write_code(StatusCode::FORBIDDEN).await.unwrap();
+                        self.write_error(CPError::AdminRequired).await.unwrap();
                         continue;
                     }
 
-                    let Ok(ip) = Ipv4Addr::from_str(subnet.as_str()) else {
+                    let Ok(ip) = IpAddr::from_str(subnet.as_str()) else {
                         self.write_bad_request().await.unwrap();
                         continue;
                     };
-                    let Ok(_subnet) = Ipv4Net::new(ip, mask) else {
+                    let Ok(_subnet) = IpNet::new(ip, mask) else {
                         self.write_bad_request().await.unwrap();
                         continue;
                     };
@@ -361,8 +957,69 @@ impl ConnectionProcessor {
                     if self.state.unban_subnet(ip, mask) {
                         self.write_code(StatusCode::NO_CONTENT).await.unwrap();
                     } else {
-                        self.write_code(StatusCode::CONFLICT).await.unwrap();
+                        self.write_error(CPError::SubnetNotBanned).await.unwrap();
+                    }
+                }
+                AuthorizedHandler::ListBlacklistedUsers { query } => {
+                    if !self.state.is_prop_admin_cred(login.as_str(), ip) {
+                        self.write_error(CPError::AdminRequired).await.unwrap();
+                        continue;
+                    }
+                    let Some(page) = Page::from_query(&query) else {
+                        self.write_bad_request().await.unwrap();
+                        continue;
+                    };
+
+                    let users = self.state.banned_users();
+                    let answer = serde_json::json!({
+                        "users": page.slice(&users),
+                        "total": users.len(),
+                        "offset": page.offset,
+                        "limit": page.limit,
+                    })
+                    .to_string();
+                    self.write_json(StatusCode::OK, &answer).await.unwrap();
+                }
+                AuthorizedHandler::ListBlacklistedSubnets { query } => {
+                    if !self.state.is_prop_admin_cred(login.as_str(), ip) {
+                        self.write_error(CPError::AdminRequired).await.unwrap();
+                        continue;
                     }
+                    let Some(page) = Page::from_query(&query) else {
+                        self.write_bad_request().await.unwrap();
+                        continue;
+                    };
+
+                    let subnets = self.state.banned_subnet_rules();
+                    let answer = serde_json::json!({
+                        "subnets": page.slice(&subnets),
+                        "total": subnets.len(),
+                        "offset": page.offset,
+                        "limit": page.limit,
+                    })
+                    .to_string();
+                    self.write_json(StatusCode::OK, &answer).await.unwrap();
+                }
+                AuthorizedHandler::CheckIp { ip: checked } => {
+                    if !self.state.is_prop_admin_cred(login.as_str(), ip) {
+                        self.write_error(CPError::AdminRequired).await.unwrap();
+                        continue;
+                    }
+
+                    let Ok(checked) = IpAddr::from_str(checked.as_str()) else {
+                        self.write_bad_request().await.unwrap();
+                        continue;
+                    };
+
+                    let rule = self.state.banned_by(checked.to_canonical());
+                    let answer = serde_json::json!({
+                        "ip": checked,
+                        "banned": rule.is_some(),
+                        "rule": rule.as_ref().map(|rule| rule.subnet),
+                        "ban": rule.map(|rule| rule.ban),
+                    })
+                    .to_string();
+                    self.write_json(StatusCode::OK, &answer).await.unwrap();
                 }
             };
 
@@ -370,62 +1027,144 @@ impl ConnectionProcessor {
         }
     }
 
-    async fn write_bad_request(&mut self) -> Result<(), CPError> {
-        self.write_code(StatusCode::BAD_REQUEST).await?;
+    // responses are written in request order, the output is flushed before
+    // waiting for the next request or once it grows large
+    async fn flush_if_full(&mut self) -> Result<(), CPError> {
+        if self.out.len() >= MAX_PENDING_OUTPUT {
+            if let Err(e) = self.flush().await {
+                eprintln!("error : {e}, while writing responses");
+            }
+        }
+
         Ok(())
     }
 
-    async fn write_code(&mut self, code: StatusCode) -> Result<(), CPError> {
-        let answer = format!(
-            "HTTP/1.1 {} {}\r\nServer: Huyak-huyak\r\nContent-Length: 0\r\nConnection: keep-alive\r\n\r\n",
-            code.as_u16(),
-            code.canonical_reason().unwrap_or("OK")
-        );
+    async fn flush(&mut self) -> std::io::Result<()> {
+        if self.out.is_empty() {
+            return Ok(());
+        }
+        let out = std::mem::take(&mut self.out);
+        let (res, mut out) = self.stream.write_all(out).await;
+        out.clear();
+        self.out = out;
+        res.map(|_| ())
+    }
 
-        if let (Err(e), _) = self.stream.write_all(answer.into_bytes()).await {
-            eprintln!(
-                "error : {},  while writing status_code: {}",
-                e,
-                code.as_u16()
-            );
+    // answers to the requests buffered so far are sent before waiting for more input
+    async fn flush_in_time(&mut self) -> std::io::Result<()> {
+        match monoio::time::timeout(self.limits.write_timeout(), self.flush()).await {
+            Ok(res) => res,
+            Err(_) => Err(std::io::ErrorKind::TimedOut.into()),
         }
+    }
 
-        Ok(())
+    // reads after the bytes already in `buf`, a plain read of a Vec overwrites it
+    async fn read_more(&mut self, mut buf: Vec<u8>) -> (std::io::Result<usize>, Vec<u8>) {
+        if buf.capacity() - buf.len() < MIN_READ_SIZE {
+            buf.reserve(INIT_READ_SIZE);
+        }
+        let len = buf.len();
+        let (res, buf): (_, SliceMut<Vec<u8>>) = self.stream.read(buf.slice_mut(len..)).await;
+        (res, buf.into_inner())
     }
 
-    async fn write_auth_token(&mut self, code: StatusCode, body: String) -> Result<(), CPError> {
-        let answer = format!(
-            "HTTP/1.1 {} {}\r\nServer: Huyak-huyak\r\nContent-type: application/json\r\nConnection: keep-alive\r\nContent-Length: {}\r\n\r\n\"{}\"",
-            code.as_u16(),
-            code.canonical_reason().unwrap_or("OK"),
-            body.len() + 2,
-            body
+    // the buffer is lost if the deadline comes first, the connection can only be closed then;
+    // pending answers are flushed first, outside of the deadline
+    async fn read_until(
+        &mut self,
+        buf: Vec<u8>,
+        deadline: Instant,
+    ) -> Option<(std::io::Result<usize>, Vec<u8>)> {
+        if let Err(e) = self.flush_in_time().await {
+            return Some((Err(e), buf));
+        }
+        monoio::time::timeout_at(deadline, self.read_more(buf))
+            .await
+            .ok()
+    }
+
+    async fn write_bad_request(&mut self) -> Result<(), CPError> {
+        self.write_error(CPError::BadRequest).await
+    }
+
+    // the rest of the input can't be framed after these, the connection is closed
+    async fn reject(&mut self, error: CPError) -> Result<(), CPError> {
+        self.keep_alive = false;
+        self.write_error(error).await
+    }
+
+    async fn write_error(&mut self, error: CPError) -> Result<(), CPError> {
+        error.write(
+            &mut self.out,
+            self.keep_alive,
+            &self.extra_headers,
+            self.problem_details,
         );
+        self.extra_headers.clear();
 
-        if let (Err(e), _) = self.stream.write_all(answer.into_bytes()).await {
-            eprintln!("error : {},  while writing json: {}", e, body);
-        }
+        self.flush_if_full().await
+    }
 
-        Ok(())
+    async fn write_code(&mut self, code: StatusCode) -> Result<(), CPError> {
+        Response::new(&mut self.out, code, self.keep_alive)
+            .raw_headers(&self.extra_headers)
+            .empty();
+        self.extra_headers.clear();
+
+        self.flush_if_full().await
     }
 
-    async fn write_json(&mut self, code: StatusCode, body: &str) -> Result<(), CPError> {
-        let answer = format!(
-            "HTTP/1.1 {} {}\r\nServer: Huyak-huyak\r\nContent-type: application/json\r\nConnection: keep-alive\r\nContent-Length: {}\r\n\r\n{}",
-            code.as_u16(),
-            code.canonical_reason().unwrap_or("OK"),
-            body.len(),
-            body
+    fn set_rate_limit_headers(&mut self, quota: &Quota) {
+        self.extra_headers.clear();
+        let _ = write!(
+            self.extra_headers,
+            "X-RateLimit-Limit: {}\r\nX-RateLimit-Remaining: {}\r\nX-RateLimit-Reset: {}\r\n",
+            quota.limit,
+            quota.remaining,
+            ceil_secs(quota.reset)
         );
+    }
 
-        if let (Err(e), _) = self.stream.write_all(answer.into_bytes()).await {
-            eprintln!("error : {},  while writing json: {}", e, body);
-        }
+    async fn write_too_many_requests(
+        &mut self,
+        error: CPError,
+        retry_after: Duration,
+    ) -> Result<(), CPError> {
+        let _ = write!(
+            self.extra_headers,
+            "Retry-After: {}\r\n",
+            ceil_secs(retry_after).max(1)
+        );
+        self.write_error(error).await
+    }
 
-        Ok(())
+    // body stays the bare access token for compatibility, refresh token goes to the header
+    async fn write_auth_token(&mut self, code: StatusCode, tokens: Tokens) -> Result<(), CPError> {
+        let token = tokens.access_token.as_bytes();
+        Response::new(&mut self.out, code, self.keep_alive)
+            .header("X-Refresh-Token", tokens.refresh_token)
+            .raw_headers(&self.extra_headers)
+            .body("application/json", &[b"\"", token, b"\""]);
+        self.extra_headers.clear();
+
+        self.flush_if_full().await
+    }
+
+    async fn write_json(&mut self, code: StatusCode, body: &str) -> Result<(), CPError> {
+        Response::new(&mut self.out, code, self.keep_alive)
+            .raw_headers(&self.extra_headers)
+            .body("application/json", &[body.as_bytes()]);
+        self.extra_headers.clear();
+
+        self.flush_if_full().await
     }
 }
 
+// headers count whole seconds, a partial one is a whole one to wait
+fn ceil_secs(duration: Duration) -> u64 {
+    duration.as_secs() + u64::from(duration.subsec_nanos() > 0)
+}
+
 struct StackLowerCaseStr {
     buffer: [u8; 32],
     len: usize,
@@ -435,8 +1174,9 @@ impl StackLowerCaseStr {
     fn from_str(url: &str) -> StackLowerCaseStr {
         let len = if url.len() > 32 { 32 } else { url.len() };
 
+        // longer names are cut, none of the known ones is that long
         let mut buffer = [0u8; 32];
-        for (idx, &c) in url.as_bytes().iter().enumerate() {
+        for (idx, &c) in url.as_bytes()[..len].iter().enumerate() {
             buffer[idx] = c.to_ascii_lowercase();
         }
 
@@ -447,3 +1187,118 @@ impl StackLowerCaseStr {
         unsafe { std::str::from_utf8_unchecked(&self.buffer[..self.len]) }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use http::StatusCode;
+
+    use crate::service::{accepts_problem_json, CPError, StackLowerCaseStr};
+
+    #[test]
+    fn test_lower_case_str() {
+        assert_eq!(
+            "x-api-key",
+            StackLowerCaseStr::from_str("X-Api-Key").as_str()
+        );
+
+        let long = "X-Very-Long-Header-Name-Sent-By-Some-Proxy";
+        assert_eq!(
+            &long[..32].to_ascii_lowercase(),
+            StackLowerCaseStr::from_str(long).as_str()
+        );
+    }
+
+    #[test]
+    fn test_accept() {
+        let accepts = |value: &str| accepts_problem_json(value.as_bytes());
+        assert!(accepts("application/problem+json"));
+        assert!(accepts("application/json, Application/Problem+JSON;q=0.5"));
+        assert!(accepts(
+            "text/html; charset=utf-8, application/problem+json ; q=1"
+        ));
+
+        assert!(!accepts("application/problem+json;q=0"));
+        assert!(!accepts("application/problem+json; q=0.0, text/html"));
+        assert!(!accepts("application/problem+json;q=x"));
+        assert!(!accepts("*/*"));
+        assert!(!accepts("application/problem+jsonx"));
+        assert!(!accepts("text/plain; x=application/problem+json"));
+    }
+
+    #[test]
+    fn test_error_details() {
+        let cases = [
+            (CPError::BadRequest, 400, "bad_request"),
+            (CPError::AmbiguousBodyLength, 400, "ambiguous_body_length"),
+            (CPError::UnknownClientIp, 403, "unknown_client_ip"),
+            (CPError::IpBanned, 403, "ip_banned"),
+            (CPError::InvalidCredentials, 403, "invalid_credentials"),
+            (CPError::InvalidRefreshToken, 403, "invalid_refresh_token"),
+            (CPError::MissingToken, 403, "missing_token"),
+            (CPError::InvalidToken, 403, "invalid_token"),
+            (CPError::TokenExpired, 401, "token_expired"),
+            (CPError::UnknownUser, 403, "unknown_user"),
+            (CPError::UserBanned, 403, "user_banned"),
+            (CPError::WrongCountry, 403, "wrong_country"),
+            (CPError::AdminRequired, 403, "admin_required"),
+            (CPError::NotFound, 404, "not_found"),
+            (CPError::UserNotFound, 404, "user_not_found"),
+            (CPError::UserExists, 409, "user_exists"),
+            (CPError::AlreadyBanned, 409, "already_banned"),
+            (CPError::UserNotBanned, 404, "not_banned"),
+            (CPError::SubnetNotBanned, 409, "not_banned"),
+            (CPError::KeyExists, 409, "key_exists"),
+            (CPError::KeyGenerationFailed, 500, "key_generation_failed"),
+            (CPError::RateLimited, 429, "rate_limited"),
+            (CPError::LoginThrottled, 429, "login_throttled"),
+            (CPError::PayloadTooLarge, 413, "payload_too_large"),
+            (CPError::HeadersTooLarge, 431, "headers_too_large"),
+            (
+                CPError::UnsupportedTransferEncoding,
+                501,
+                "unsupported_transfer_encoding",
+            ),
+            (CPError::ExpectationFailed, 417, "expectation_failed"),
+            (CPError::RequestTimeout, 408, "request_timeout"),
+        ];
+
+        for (error, status, code) in cases {
+            let (details_status, details_code, message) = error.details();
+            assert_eq!((status, code), (details_status.as_u16(), details_code));
+            assert!(!message.is_empty());
+        }
+    }
+
+    #[test]
+    fn test_error_body() {
+        let mut out = Vec::new();
+        CPError::IpBanned.write(&mut out, true, "Retry-After: 5\r\n", false);
+        let expected =
+            "HTTP/1.1 403 Forbidden\r\nServer: Huyak-huyak\r\nConnection: keep-alive\r\n\
+            Retry-After: 5\r\nContent-Length: 0\r\n\r\n";
+        assert_eq!(expected, String::from_utf8(out).unwrap());
+
+        let mut out = Vec::new();
+        CPError::TokenExpired.write(&mut out, false, "", true);
+        let out = String::from_utf8(out).unwrap();
+        let (head, body) = out.split_once("\r\n\r\n").unwrap();
+        assert!(head.starts_with("HTTP/1.1 401 Unauthorized\r\n"));
+        assert!(head.contains("\r\nConnection: close\r\n"));
+        assert!(head.contains(
+            "\r\nWWW-Authenticate: Bearer error=\"invalid_token\", \
+            error_description=\"the access token has expired\"\r\n"
+        ));
+        assert!(head.contains("\r\nContent-Type: application/problem+json\r\n"));
+        assert!(head.ends_with(&format!("Content-Length: {}", body.len())));
+
+        let body: serde_json::Value = serde_json::from_str(body).unwrap();
+        assert_eq!(
+            serde_json::json!({
+                "status": StatusCode::UNAUTHORIZED.as_u16(),
+                "code": "token_expired",
+                "message": "the access token has expired",
+            }),
+            body
+        );
+    }
+}
@@ -0,0 +1,318 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use base64::prelude::*;
+use dashmap::DashMap;
+use jwt_simple::prelude::{Clock, Duration, UnixTimeStamp};
+use smol_str::SmolStr;
+
+const REFRESH_TOKEN_LEN: usize = 32;
+const SESSION_ID_LEN: usize = 12;
+
+// expired sessions are dropped on every PRUNE_PERIOD-th login
+const PRUNE_PERIOD: usize = 1024;
+
+// only hashes of refresh tokens are kept, a memory dump doesn't leak usable tokens
+type TokenHash = [u8; 32];
+
+struct Session {
+    login: SmolStr,
+    nonce: SmolStr,
+    expires_at: UnixTimeStamp,
+    // all refresh tokens ever issued for the session, including rotated ones
+    refresh_tokens: Vec<TokenHash>,
+}
+
+struct RefreshEntry {
+    sid: SmolStr,
+    used: bool,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum RefreshError {
+    Invalid,
+    // already rotated token presented again, the session is revoked
+    Reused,
+}
+
+pub struct Owner {
+    pub sid: SmolStr,
+    pub login: SmolStr,
+    pub nonce: SmolStr,
+}
+
+pub struct Refreshed {
+    pub sid: SmolStr,
+    pub login: SmolStr,
+    pub nonce: SmolStr,
+    pub refresh_token: String,
+}
+
+/// Login sessions with rotating opaque refresh tokens.
+pub struct Sessions {
+    sessions: DashMap<SmolStr, Session>,
+    refresh_tokens: DashMap<TokenHash, RefreshEntry>,
+    ttl: Duration,
+    created: AtomicUsize,
+}
+
+impl Sessions {
+    pub fn new(ttl: Duration) -> Sessions {
+        Sessions {
+            sessions: DashMap::with_shard_amount(16),
+            refresh_tokens: DashMap::with_shard_amount(16),
+            ttl,
+            created: AtomicUsize::new(0),
+        }
+    }
+
+    /// Starts a new session for `login`, returns session id and the first refresh token.
+    pub fn create(&self, login: &str, nonce: &str) -> (SmolStr, String) {
+        if self.created.fetch_add(1, Ordering::Relaxed) % PRUNE_PERIOD == PRUNE_PERIOD - 1 {
+            self.prune();
+        }
+
+        let sid: SmolStr = random_token::<SESSION_ID_LEN>().into();
+        let (refresh_token, hash) = new_refresh_token();
+
+        self.refresh_tokens.insert(
+            hash,
+            RefreshEntry {
+                sid: sid.clone(),
+                used: false,
+            },
+        );
+        self.sessions.insert(
+            sid.clone(),
+            Session {
+                login: login.into(),
+                nonce: nonce.into(),
+                expires_at: Clock::now_since_epoch() + self.ttl,
+                refresh_tokens: vec![hash],
+            },
+        );
+
+        (sid, refresh_token)
+    }
+
+    pub fn is_active(&self, sid: &str) -> bool {
+        self.sessions
+            .get(sid)
+            .is_some_and(|session| session.expires_at > Clock::now_since_epoch())
+    }
+
+    /// Session `refresh_token` belongs to, the token stays valid. An already
+    /// rotated token revokes the session, as in `rotate`.
+    pub fn owner(&self, refresh_token: &str) -> Result<Owner, RefreshError> {
+        let hash = hmac_sha256::Hash::hash(refresh_token.as_bytes());
+
+        let (sid, was_used) = {
+            let entry = self
+                .refresh_tokens
+                .get(&hash)
+                .ok_or(RefreshError::Invalid)?;
+            (entry.sid.clone(), entry.used)
+        };
+
+        if was_used {
+            self.revoke(&sid);
+            return Err(RefreshError::Reused);
+        }
+
+        let session = self
+            .sessions
+            .get(&sid)
+            .filter(|session| session.expires_at > Clock::now_since_epoch())
+            .ok_or(RefreshError::Invalid)?;
+
+        Ok(Owner {
+            login: session.login.clone(),
+            nonce: session.nonce.clone(),
+            sid,
+        })
+    }
+
+    /// Exchanges `refresh_token` for a new one. Every refresh token is accepted
+    /// only once, a second attempt means it was stolen and revokes the whole session.
+    pub fn rotate(&self, refresh_token: &str) -> Result<Refreshed, RefreshError> {
+        let hash = hmac_sha256::Hash::hash(refresh_token.as_bytes());
+
+        // never hold locks of both maps at the same time
+        let (sid, was_used) = {
+            let mut entry = self
+                .refresh_tokens
+                .get_mut(&hash)
+                .ok_or(RefreshError::Invalid)?;
+            let was_used = std::mem::replace(&mut entry.used, true);
+            (entry.sid.clone(), was_used)
+        };
+
+        if was_used {
+            self.revoke(&sid);
+            return Err(RefreshError::Reused);
+        }
+
+        let (refresh_token, new_hash) = new_refresh_token();
+        self.refresh_tokens.insert(
+            new_hash,
+            RefreshEntry {
+                sid: sid.clone(),
+                used: false,
+            },
+        );
+
+        let (login, nonce) = {
+            let session = self.sessions.get_mut(&sid).and_then(|mut session| {
+                if session.expires_at <= Clock::now_since_epoch() {
+                    return None;
+                }
+                session.refresh_tokens.push(new_hash);
+                Some((session.login.clone(), session.nonce.clone()))
+            });
+
+            match session {
+                Some(session) => session,
+                None => {
+                    // session was revoked or has expired in between
+                    self.revoke(&sid);
+                    self.refresh_tokens.remove(&new_hash);
+                    return Err(RefreshError::Invalid);
+                }
+            }
+        };
+
+        Ok(Refreshed {
+            sid,
+            login,
+            nonce,
+            refresh_token,
+        })
+    }
+
+    /// Drops the session, both its access and refresh tokens stop working.
+    pub fn revoke(&self, sid: &str) -> bool {
+        let Some((_, session)) = self.sessions.remove(sid) else {
+            return false;
+        };
+
+        for hash in session.refresh_tokens.iter() {
+            self.refresh_tokens.remove(hash);
+        }
+
+        true
+    }
+
+    /// Drops all sessions of `login`.
+    pub fn revoke_login(&self, login: &str) {
+        let sids: Vec<SmolStr> = self
+            .sessions
+            .iter()
+            .filter(|session| session.login == login)
+            .map(|session| session.key().clone())
+            .collect();
+
+        for sid in sids {
+            self.revoke(&sid);
+        }
+    }
+
+    fn prune(&self) {
+        let now = Clock::now_since_epoch();
+        let expired: Vec<SmolStr> = self
+            .sessions
+            .iter()
+            .filter(|session| session.expires_at <= now)
+            .map(|session| session.key().clone())
+            .collect();
+
+        for sid in expired {
+            self.revoke(&sid);
+        }
+    }
+}
+
+fn new_refresh_token() -> (String, TokenHash) {
+    let token = random_token::<REFRESH_TOKEN_LEN>();
+    let hash = hmac_sha256::Hash::hash(token.as_bytes());
+    (token, hash)
+}
+
+fn random_token<const LEN: usize>() -> String {
+    let mut bytes = [0u8; LEN];
+    getrandom::getrandom(&mut bytes).expect("the OS random source is unavailable");
+    BASE64_URL_SAFE_NO_PAD.encode(bytes)
+}
+
+#[cfg(test)]
+mod test {
+    use jwt_simple::prelude::Duration;
+
+    use crate::session::{RefreshError, Sessions};
+
+    #[test]
+    fn test_rotate() {
+        let sessions = Sessions::new(Duration::from_hours(1));
+        let (sid, first) = sessions.create("abcde", "nonce");
+        assert!(sessions.is_active(&sid));
+
+        let owner = sessions.owner(&first).unwrap();
+        assert_eq!(
+            (&sid, "abcde", "nonce"),
+            (&owner.sid, &*owner.login, &*owner.nonce)
+        );
+        // looking the owner up doesn't consume the token
+        let refreshed = sessions.rotate(&first).unwrap();
+        assert_eq!(sid, refreshed.sid);
+        assert_eq!("abcde", refreshed.login);
+
+        let second = refreshed.refresh_token;
+        let third = sessions.rotate(&second).unwrap().refresh_token;
+
+        // reuse of a rotated token kills the session with all its tokens
+        assert_eq!(
+            Err(RefreshError::Reused),
+            sessions.rotate(&first).map(|_| ())
+        );
+        assert!(!sessions.is_active(&sid));
+        assert_eq!(
+            Err(RefreshError::Invalid),
+            sessions.owner(&third).map(|_| ())
+        );
+        assert_eq!(
+            Err(RefreshError::Invalid),
+            sessions.rotate(&third).map(|_| ())
+        );
+        assert_eq!(
+            Err(RefreshError::Invalid),
+            sessions.rotate("unknown").map(|_| ())
+        );
+    }
+
+    #[test]
+    fn test_revoke_login() {
+        let sessions = Sessions::new(Duration::from_hours(1));
+        let (first, _) = sessions.create("abcde", "nonce");
+        let (second, token) = sessions.create("abcde", "nonce");
+        let (other, _) = sessions.create("fghij", "nonce");
+
+        sessions.revoke_login("abcde");
+        assert!(!sessions.is_active(&first));
+        assert!(!sessions.is_active(&second));
+        assert!(sessions.is_active(&other));
+        assert_eq!(
+            Err(RefreshError::Invalid),
+            sessions.rotate(&token).map(|_| ())
+        );
+    }
+
+    #[test]
+    fn test_expired() {
+        let sessions = Sessions::new(Duration::from_secs(0));
+        let (sid, token) = sessions.create("abcde", "nonce");
+
+        assert!(!sessions.is_active(&sid));
+        assert_eq!(
+            Err(RefreshError::Invalid),
+            sessions.rotate(&token).map(|_| ())
+        );
+    }
+}
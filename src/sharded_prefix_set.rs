@@ -1,47 +1,332 @@
-// use std::sync::RwLock;
-// 
-// use iprange::IpRange;
-// 
-// const SHARD_AMOUNT_SHIFT: usize = 7;
-// 
-// // in addition to sharding this trie must have lower depth
-// pub struct ShardedPrefixSet {
-//     prefix_set: [RwLock<IpRange<ipnet::Ipv4Net>>; 1 << SHARD_AMOUNT_SHIFT],
-// }
-// 
-// impl ShardedPrefixSet {
-//     pub fn new() -> ShardedPrefixSet {
-//         assert!(SHARD_AMOUNT_SHIFT <= 8);
-//         ShardedPrefixSet {
-//             prefix_set: std::array::from_fn(|_| RwLock::new(IpRange::new())),
-//         }
-//     }
-// 
-//     #[inline(always)]
-//     fn shard_id(&self, subnet: ipnet::Ipv4Net) -> usize {
-//         let octet = subnet.addr().octets()[0];
-//         let shard = octet >> (8 - SHARD_AMOUNT_SHIFT);
-//         shard as usize
-//     }
-// 
-//     pub fn contains(&self, subnet: ipnet::Ipv4Net) -> bool {
-//         self.prefix_set[self.shard_id(subnet)]
-//             .read()
-//             .unwrap()
-//             .contains(&subnet)
-//     }
-// 
-//     pub fn insert(&self, subnet: ipnet::Ipv4Net) {
-//         self.prefix_set[self.shard_id(subnet)]
-//             .write()
-//             .unwrap()
-//             .add(subnet);
-//     }
-// 
-//     pub fn remove(&self, subnet: ipnet::Ipv4Net) {
-//         self.prefix_set[self.shard_id(subnet)]
-//             .write()
-//             .unwrap()
-//             .remove(subnet);
-//     }
-// }
+use std::sync::RwLock;
+
+use ipnet::{Ipv4Net, Ipv6Net};
+
+// shard is chosen by SHARD_BITS of the address starting at Prefix::SHARD_OFFSET
+const SHARD_BITS: u8 = 8;
+const SHARD_AMOUNT: usize = 1 << SHARD_BITS;
+
+/// Network prefix stored in the trie, bits are left-aligned into u128.
+pub trait Prefix: Copy + Ord {
+    type Addr: Copy;
+    // first bit of the shard key
+    const SHARD_OFFSET: u8;
+
+    fn addr_key(addr: Self::Addr) -> u128;
+    fn key(&self) -> u128;
+    fn len(&self) -> u8;
+    fn from_key(key: u128, len: u8) -> Self;
+}
+
+impl Prefix for Ipv4Net {
+    type Addr = std::net::Ipv4Addr;
+    const SHARD_OFFSET: u8 = 0;
+
+    fn addr_key(addr: Self::Addr) -> u128 {
+        (u32::from(addr) as u128) << 96
+    }
+
+    fn key(&self) -> u128 {
+        Self::addr_key(self.network())
+    }
+
+    fn len(&self) -> u8 {
+        self.prefix_len()
+    }
+
+    fn from_key(key: u128, len: u8) -> Self {
+        Ipv4Net::new(((key >> 96) as u32).into(), len).unwrap()
+    }
+}
+
+impl Prefix for Ipv6Net {
+    type Addr = std::net::Ipv6Addr;
+    // nearly all routed addresses share the first bits (2000::/3), RIRs
+    // hand out /12../32 blocks, so the bits below /16 spread much better
+    const SHARD_OFFSET: u8 = 16;
+
+    fn addr_key(addr: Self::Addr) -> u128 {
+        addr.into()
+    }
+
+    fn key(&self) -> u128 {
+        self.network().into()
+    }
+
+    fn len(&self) -> u8 {
+        self.prefix_len()
+    }
+
+    fn from_key(key: u128, len: u8) -> Self {
+        Ipv6Net::new(key.into(), len).unwrap()
+    }
+}
+
+#[inline(always)]
+fn bit(key: u128, idx: u8) -> usize {
+    ((key >> (127 - idx)) & 1) as usize
+}
+
+#[derive(Default)]
+struct Node {
+    children: [Option<Box<Node>>; 2],
+    terminal: bool,
+}
+
+impl Node {
+    fn is_empty(&self) -> bool {
+        !self.terminal && self.children.iter().all(Option::is_none)
+    }
+
+    // returns false if the prefix is already there
+    fn insert(&mut self, key: u128, len: u8) -> bool {
+        let mut node = self;
+        for idx in 0..len {
+            node = node.children[bit(key, idx)].get_or_insert_with(Default::default);
+        }
+
+        !std::mem::replace(&mut node.terminal, true)
+    }
+
+    // drops branches left without prefixes
+    fn remove(&mut self, key: u128, len: u8, depth: u8) -> bool {
+        if depth == len {
+            return std::mem::replace(&mut self.terminal, false);
+        }
+
+        let child = &mut self.children[bit(key, depth)];
+        let Some(node) = child else {
+            return false;
+        };
+
+        let removed = node.remove(key, len, depth + 1);
+        if removed && node.is_empty() {
+            *child = None;
+        }
+
+        removed
+    }
+
+    fn longest_match(&self, key: u128) -> Option<u8> {
+        let mut node = self;
+        let mut found = node.terminal.then_some(0);
+        for idx in 0..128 {
+            let Some(next) = &node.children[bit(key, idx)] else {
+                break;
+            };
+            node = next;
+            if node.terminal {
+                found = Some(idx + 1);
+            }
+        }
+
+        found
+    }
+
+    fn collect(&self, key: u128, depth: u8, out: &mut Vec<(u128, u8)>) {
+        if self.terminal {
+            out.push((key, depth));
+        }
+
+        for (bit, child) in self.children.iter().enumerate() {
+            if let Some(child) = child {
+                child.collect(key | ((bit as u128) << (127 - depth)), depth + 1, out);
+            }
+        }
+    }
+}
+
+/// Set of networks with longest-prefix lookup, a lookup walks at most
+/// one node per address bit whatever the size of the set.
+///
+/// Readers only take the read lock of one shard. Prefixes not reaching
+/// the end of the shard key are stored in every shard they cover, so
+/// writers must be serialized by the caller.
+pub struct ShardedPrefixSet<P: Prefix> {
+    shards: [RwLock<Node>; SHARD_AMOUNT],
+    _prefix: std::marker::PhantomData<P>,
+}
+
+impl<P: Prefix> Default for ShardedPrefixSet<P> {
+    fn default() -> Self {
+        ShardedPrefixSet::new()
+    }
+}
+
+impl<P: Prefix> ShardedPrefixSet<P> {
+    pub fn new() -> ShardedPrefixSet<P> {
+        ShardedPrefixSet {
+            shards: std::array::from_fn(|_| RwLock::new(Node::default())),
+            _prefix: std::marker::PhantomData,
+        }
+    }
+
+    #[inline(always)]
+    fn shard_id(key: u128) -> usize {
+        ((key << P::SHARD_OFFSET) >> (128 - SHARD_BITS as u32)) as usize
+    }
+
+    // shards covered by the prefix, host bits of the key are zero
+    fn shard_range(prefix: &P) -> std::ops::Range<usize> {
+        let first = Self::shard_id(prefix.key());
+        let free_bits = (P::SHARD_OFFSET + SHARD_BITS).saturating_sub(prefix.len());
+        let span = 1 << free_bits.min(SHARD_BITS);
+        first..first + span
+    }
+
+    /// Returns false if exactly this prefix is already in the set.
+    pub fn insert(&self, prefix: P) -> bool {
+        let mut inserted = false;
+        for shard in Self::shard_range(&prefix) {
+            let mut root = self.shards[shard].write().unwrap();
+            inserted |= root.insert(prefix.key(), prefix.len());
+        }
+
+        inserted
+    }
+
+    /// Removes exactly this prefix, longer and shorter ones stay.
+    pub fn remove(&self, prefix: P) -> bool {
+        let mut removed = false;
+        for shard in Self::shard_range(&prefix) {
+            let mut root = self.shards[shard].write().unwrap();
+            removed |= root.remove(prefix.key(), prefix.len(), 0);
+        }
+
+        removed
+    }
+
+    /// The most specific prefix containing `addr`.
+    pub fn longest_match(&self, addr: P::Addr) -> Option<P> {
+        let key = P::addr_key(addr);
+        let root = self.shards[Self::shard_id(key)].read().unwrap();
+        let len = root.longest_match(key)?;
+        drop(root);
+
+        let mask = u128::MAX.checked_shl(128 - len as u32).unwrap_or(0);
+        Some(P::from_key(key & mask, len))
+    }
+
+    /// All prefixes of the set in ascending order.
+    pub fn prefixes(&self) -> Vec<P> {
+        let mut raw = Vec::new();
+        for shard in self.shards.iter() {
+            shard.read().unwrap().collect(0, 0, &mut raw);
+        }
+
+        let mut prefixes: Vec<P> = raw
+            .into_iter()
+            .map(|(key, len)| P::from_key(key, len))
+            .collect();
+        // short prefixes are collected from every shard they cover
+        prefixes.sort_unstable();
+        prefixes.dedup();
+        prefixes
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    use ipnet::{Ipv4Net, Ipv6Net};
+
+    use crate::sharded_prefix_set::ShardedPrefixSet;
+
+    fn net(s: &str) -> Ipv4Net {
+        s.parse().unwrap()
+    }
+
+    fn addr(s: &str) -> Ipv4Addr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_longest_match() {
+        let set = ShardedPrefixSet::new();
+        assert!(set.insert(net("10.0.0.0/8")));
+        assert!(set.insert(net("10.1.0.0/16")));
+        assert!(set.insert(net("10.1.2.3/32")));
+        assert!(!set.insert(net("10.0.0.0/8")));
+
+        assert_eq!(Some(net("10.0.0.0/8")), set.longest_match(addr("10.2.0.1")));
+        assert_eq!(
+            Some(net("10.1.0.0/16")),
+            set.longest_match(addr("10.1.2.4"))
+        );
+        assert_eq!(
+            Some(net("10.1.2.3/32")),
+            set.longest_match(addr("10.1.2.3"))
+        );
+        assert_eq!(None, set.longest_match(addr("11.0.0.1")));
+
+        assert!(set.remove(net("10.1.0.0/16")));
+        assert!(!set.remove(net("10.1.0.0/16")));
+        assert_eq!(Some(net("10.0.0.0/8")), set.longest_match(addr("10.1.2.4")));
+        assert_eq!(vec![net("10.0.0.0/8"), net("10.1.2.3/32")], set.prefixes());
+    }
+
+    #[test]
+    fn test_short_prefix() {
+        // spans 16 shards, must match regardless of the first octet
+        let set = ShardedPrefixSet::new();
+        assert!(set.insert(net("16.0.0.0/4")));
+        assert!(set.longest_match(addr("16.0.0.1")).is_some());
+        assert!(set.longest_match(addr("31.255.255.255")).is_some());
+        assert!(set.longest_match(addr("32.0.0.0")).is_none());
+        assert!(set.longest_match(addr("15.255.255.255")).is_none());
+
+        assert!(set.insert(net("0.0.0.0/0")));
+        assert!(set.longest_match(addr("200.1.1.1")).is_some());
+        assert_eq!(vec![net("0.0.0.0/0"), net("16.0.0.0/4")], set.prefixes());
+
+        assert!(set.remove(net("0.0.0.0/0")));
+        assert!(set.remove(net("16.0.0.0/4")));
+        assert!(set.longest_match(addr("16.0.0.1")).is_none());
+        assert!(set.prefixes().is_empty());
+    }
+
+    #[test]
+    fn test_ipv6() {
+        let set = ShardedPrefixSet::<Ipv6Net>::new();
+        assert!(set.insert("2a00:1::/32".parse().unwrap()));
+        assert!(set.insert("::/1".parse().unwrap()));
+
+        let lookup = |s: &str| set.longest_match(s.parse::<Ipv6Addr>().unwrap());
+        assert_eq!(Some("2a00:1::/32".parse().unwrap()), lookup("2a00:1::5"));
+        assert_eq!(Some("::/1".parse().unwrap()), lookup("2a00:2::5"));
+        assert_eq!(None, lookup("8000::1"));
+    }
+
+    #[test]
+    fn test_ipv6_shards() {
+        type Set = ShardedPrefixSet<Ipv6Net>;
+        let net = |s: &str| s.parse::<Ipv6Net>().unwrap();
+
+        // neighbouring allocations land in different shards
+        let shard = |s: &str| Set::shard_id(s.parse::<Ipv6Addr>().unwrap().into());
+        assert_ne!(shard("2a00:1000::1"), shard("2a00:2000::1"));
+        assert_eq!(0x10, shard("2a00:1000::1"));
+
+        assert_eq!(0x10..0x11, Set::shard_range(&net("2a00:1000::/24")));
+        assert_eq!(0x10..0x20, Set::shard_range(&net("2a00:1000::/20")));
+        assert_eq!(0..256, Set::shard_range(&net("2a00::/16")));
+        assert_eq!(0..256, Set::shard_range(&net("2000::/3")));
+
+        let set = Set::new();
+        assert!(set.insert(net("2a00::/12")));
+        assert!(set.insert(net("2a00:1000::/20")));
+        let lookup = |s: &str| set.longest_match(s.parse::<Ipv6Addr>().unwrap());
+        assert_eq!(Some(net("2a00:1000::/20")), lookup("2a00:1fff::1"));
+        assert_eq!(Some(net("2a00::/12")), lookup("2a0f:ffff::1"));
+        assert_eq!(None, lookup("2a10::1"));
+        assert_eq!(
+            vec![net("2a00::/12"), net("2a00:1000::/20")],
+            set.prefixes()
+        );
+
+        assert!(set.remove(net("2a00::/12")));
+        assert_eq!(None, lookup("2a0f:ffff::1"));
+        assert_eq!(Some(net("2a00:1000::/20")), lookup("2a00:1fff::1"));
+    }
+}
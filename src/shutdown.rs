@@ -0,0 +1,127 @@
+use std::{
+    cell::RefCell,
+    future::Future,
+    pin::Pin,
+    sync::atomic::{AtomicBool, Ordering},
+    task::{Context, Poll, Waker},
+    time::Duration,
+};
+
+use signal_hook::{
+    consts::{SIGINT, SIGTERM},
+    iterator::Signals,
+};
+
+// how often the worker looks at the shutdown flag, signal threads can't wake monoio tasks
+const POLL_PERIOD: Duration = Duration::from_millis(50);
+
+static SHUTDOWN: AtomicBool = AtomicBool::new(false);
+
+pub fn request() {
+    SHUTDOWN.store(true, Ordering::Relaxed);
+}
+
+pub fn is_requested() -> bool {
+    SHUTDOWN.load(Ordering::Relaxed)
+}
+
+thread_local! {
+    static WAITERS: RefCell<Waiters> = RefCell::new(Waiters::default());
+}
+
+/// Tasks of the current worker waiting for shutdown.
+#[derive(Default)]
+struct Waiters {
+    wakers: Vec<Option<Waker>>,
+    free: Vec<usize>,
+    // one task per worker polls the flag and wakes the rest
+    watching: bool,
+}
+
+/// Resolves once shutdown is requested.
+pub fn wait() -> Wait {
+    Wait { slot: None }
+}
+
+pub struct Wait {
+    slot: Option<usize>,
+}
+
+impl Future for Wait {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if is_requested() {
+            return Poll::Ready(());
+        }
+
+        WAITERS.with_borrow_mut(|waiters| {
+            let waker = Some(cx.waker().clone());
+            match self.slot {
+                Some(slot) => waiters.wakers[slot] = waker,
+                None => {
+                    let slot = match waiters.free.pop() {
+                        Some(slot) => {
+                            waiters.wakers[slot] = waker;
+                            slot
+                        }
+                        None => {
+                            waiters.wakers.push(waker);
+                            waiters.wakers.len() - 1
+                        }
+                    };
+                    self.slot = Some(slot);
+                }
+            }
+
+            if !waiters.watching {
+                waiters.watching = true;
+                monoio::spawn(watch());
+            }
+        });
+
+        Poll::Pending
+    }
+}
+
+impl Drop for Wait {
+    fn drop(&mut self) {
+        if let Some(slot) = self.slot {
+            WAITERS.with_borrow_mut(|waiters| {
+                waiters.wakers[slot] = None;
+                waiters.free.push(slot);
+            });
+        }
+    }
+}
+
+async fn watch() {
+    while !is_requested() {
+        monoio::time::sleep(POLL_PERIOD).await;
+    }
+
+    let wakers: Vec<_> = WAITERS
+        .with_borrow_mut(|waiters| waiters.wakers.iter_mut().filter_map(Option::take).collect());
+    for waker in wakers {
+        waker.wake();
+    }
+}
+
+/// First SIGTERM/SIGINT starts graceful shutdown, the second one kills the process.
+pub fn listen_signals() -> std::io::Result<()> {
+    let mut signals = Signals::new([SIGTERM, SIGINT])?;
+
+    std::thread::spawn(move || {
+        for signal in signals.forever() {
+            if is_requested() {
+                eprintln!("signal {signal} during shutdown, exiting");
+                std::process::exit(1);
+            }
+
+            eprintln!("signal {signal}, shutting down");
+            request();
+        }
+    });
+
+    Ok(())
+}
@@ -1,18 +1,37 @@
-use std::{collections::HashMap, net::Ipv4Addr};
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    net::IpAddr,
+    str::FromStr,
+    sync::{Mutex, OnceLock, RwLock},
+};
 
-use dashmap::{DashMap, DashSet};
-use ipnet::Ipv4Net;
+use dashmap::{mapref::entry::Entry, DashMap};
+use ipnet::{IpNet, Ipv4Net, Ipv6Net};
 use iprange::IpRange;
 use jwt_simple::{
-    algorithms::{HS256Key, MACLike},
-    reexports::coarsetime::Duration,
+    common::VerificationOptions, reexports::coarsetime::Duration, token::Token, JWTError,
 };
 use serde::{Deserialize, Serialize};
 use smol_str::SmolStr;
 
+use crate::{
+    auth_throttle::AuthThrottle,
+    ban::{unix_now, BanInfo, BanTarget, BannedSubnet, BannedUser},
+    blacklist::{self, SubnetBlacklist},
+    blocking,
+    config::{AuthConfig, RateLimitConfig},
+    journal::{Journal, Mutation},
+    keys::{Algorithm, KeyRing, SigningKey},
+    rate_limit::RateLimiter,
+    session::{RefreshError, Sessions},
+    timer_wheel::TimerWheel,
+    user::{dummy_hash, hash_off_worker, hash_password, verify_password, PasswordCheck},
+};
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct User {
     pub login: SmolStr,
+    // argon2 PHC string, or plaintext for records not yet upgraded
     #[serde(skip_serializing)]
     pub password: SmolStr,
     pub name: SmolStr,
@@ -23,10 +42,21 @@ pub struct User {
     pub is_admin: bool,
 
     #[serde(skip)]
-    pub is_banned: bool,
+    pub ban: Option<BanInfo>,
 
+    // nonces of the latest logins, the most recent one is the last
     #[serde(skip)]
-    pub nonce: SmolStr,
+    pub nonces: VecDeque<SmolStr>,
+
+    // bumped to invalidate all issued tokens of the user at once
+    #[serde(skip)]
+    pub token_generation: u64,
+}
+
+impl User {
+    pub fn is_banned(&self) -> bool {
+        self.ban.is_some()
+    }
 }
 
 fn default_is_admin() -> bool {
@@ -42,114 +72,423 @@ struct Info {
     // DeserializeOwned workaround
     login: SmolStr,
 
+    // deserialized into the standard `nonce` claim
     #[serde(skip_deserializing)]
     nonce: SmolStr,
+
+    sid: SmolStr,
+    generation: u64,
+}
+
+/// Which logins of a user keep their tokens valid.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NoncePolicy {
+    // every token is valid until it expires
+    Any,
+    // only tokens issued by the last N logins are valid
+    LastN(usize),
+}
+
+impl FromStr for NoncePolicy {
+    type Err = anyhow::Error;
+
+    // "any", "latest" or "last:N"
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let policy = match s {
+            "any" => NoncePolicy::Any,
+            "latest" => NoncePolicy::LastN(1),
+            _ => {
+                let n = s
+                    .strip_prefix("last:")
+                    .and_then(|n| n.parse().ok())
+                    .filter(|&n| n > 0)
+                    .ok_or_else(|| {
+                        anyhow::anyhow!("nonce policy {s:?}, expected any, latest or last:N")
+                    })?;
+                NoncePolicy::LastN(n)
+            }
+        };
+
+        Ok(policy)
+    }
+}
+
+impl NoncePolicy {
+    fn is_valid(&self, nonces: &VecDeque<SmolStr>, nonce: &str) -> bool {
+        match *self {
+            NoncePolicy::Any => true,
+            NoncePolicy::LastN(n) => nonces.iter().rev().take(n).any(|stored| stored == nonce),
+        }
+    }
+
+    // how many nonces must be kept per user
+    fn window(&self) -> usize {
+        match *self {
+            NoncePolicy::Any => 1,
+            NoncePolicy::LastN(n) => n,
+        }
+    }
+}
+
+pub struct TokenSettings {
+    // access token lifetime
+    pub ttl: Duration,
+    // session lifetime, refresh doesn't extend it
+    pub refresh_ttl: Duration,
+    // tolerated difference between our clock and the clocks of token consumers
+    pub clock_skew: Duration,
+    pub nonce_policy: NoncePolicy,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum AuthError {
+    // wrong credentials, unknown or banned user, all look the same
+    Invalid,
+    // locked out after failed attempts
+    Throttled { retry_after: u64 },
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum TokenError {
+    Expired,
+    Invalid,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum AccessError {
+    UnknownUser,
+    Banned,
+    // the client IP isn't in the country of the user
+    WrongCountry,
+}
+
+pub struct Identity {
+    pub login: SmolStr,
+    pub sid: SmolStr,
+}
+
+pub struct Tokens {
+    pub access_token: String,
+    pub refresh_token: String,
+}
+
+/// Networks of one country.
+#[derive(Default)]
+pub struct CountryPrefixes {
+    pub v4: IpRange<Ipv4Net>,
+    pub v6: IpRange<Ipv6Net>,
 }
 
 pub struct State {
     pub users: DashMap<SmolStr, User>,
-
-    // TODO: make sharding by first octet
-    // pub banned: ShardedPrefixSet,
-    pub first_byte_banned_subnets: DashMap<u8, Vec<ipnet::Ipv4Net>>,
-    pub root_banned_subnets: DashSet<ipnet::Ipv4Net>,
-    pub country_prefixes: HashMap<SmolStr, IpRange<ipnet::Ipv4Net>>,
-    key: HS256Key,
+    // banned users by login, updated under the lock of the user record
+    banned_users: Mutex<BTreeMap<SmolStr, BanInfo>>,
+
+    banned_subnets: SubnetBlacklist,
+    // details of subnet bans, the lock serializes blacklist writers and
+    // keeps journal records in the order of changes
+    subnet_bans: Mutex<HashMap<IpNet, BanInfo>>,
+    // lifts bans once they expire, stale timers are ignored
+    ban_expiry: Mutex<TimerWheel<BanTarget>>,
+    pub auth_throttle: AuthThrottle,
+    pub rate_limiter: RateLimiter,
+    pub country_prefixes: HashMap<SmolStr, CountryPrefixes>,
+    keys: RwLock<KeyRing>,
+    sessions: Sessions,
+    token_settings: TokenSettings,
+    journal: OnceLock<Journal>,
 }
 
 impl State {
     pub fn new(
         users: DashMap<SmolStr, User>,
-        country_prefixes: HashMap<SmolStr, IpRange<ipnet::Ipv4Net>>,
+        country_prefixes: HashMap<SmolStr, CountryPrefixes>,
+        key: SigningKey,
+        token_settings: TokenSettings,
+        auth: AuthConfig,
+        rate_limits: &RateLimitConfig,
     ) -> State {
-        use base64::prelude::*;
-
-        let key_bytes = BASE64_STANDARD
-            .decode(b"CGWpjarkRIXzCIIw5vXKc+uESy5ebrbOyVMZvftj19k=")
-            .unwrap();
-
-        let key = HS256Key::from_bytes(key_bytes.as_slice());
-
         State {
+            banned_users: Mutex::new(
+                users
+                    .iter()
+                    .filter_map(|user| Some((user.key().clone(), user.ban.clone()?)))
+                    .collect(),
+            ),
             users,
             country_prefixes,
-            first_byte_banned_subnets: DashMap::with_capacity(32), //  ShardedPrefixSet::new(),
-            root_banned_subnets: DashSet::with_capacity(4),
-            key,
+            banned_subnets: SubnetBlacklist::default(),
+            subnet_bans: Mutex::new(HashMap::new()),
+            ban_expiry: Mutex::new(TimerWheel::new(unix_now())),
+            auth_throttle: AuthThrottle::new(auth),
+            rate_limiter: RateLimiter::new(rate_limits),
+            keys: RwLock::new(KeyRing::new(key)),
+            sessions: Sessions::new(token_settings.refresh_ttl),
+            token_settings,
+            journal: OnceLock::new(),
+        }
+    }
+
+    /// From now on every mutation is written to `journal`.
+    pub fn attach_journal(&self, journal: Journal) {
+        if self.journal.set(journal).is_err() {
+            panic!("journal is already attached");
+        }
+    }
+
+    pub fn journal(&self) -> Option<&Journal> {
+        self.journal.get()
+    }
+
+    // must be called under the lock of the mutated record
+    fn log(&self, mutation: Mutation) {
+        if let Some(journal) = self.journal.get() {
+            journal.append(mutation);
         }
     }
 
-    pub fn authenticate(
+    /// Records recreating the current users and blacklists.
+    pub fn snapshot(&self) -> impl Iterator<Item = Mutation> + '_ {
+        let users = self.users.iter().map(|user| Mutation::put_user(&user));
+        let subnets: Vec<_> = self
+            .subnet_bans
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(subnet, ban)| Mutation::BanSubnet {
+                network: subnet.addr(),
+                mask: subnet.prefix_len(),
+                ban: ban.clone(),
+            })
+            .collect();
+
+        users.chain(subnets)
+    }
+
+    pub fn jwks(&self) -> String {
+        self.keys.read().unwrap().jwks().to_string()
+    }
+
+    /// Algorithm of the active key, new keys must use the same one.
+    pub fn signing_algorithm(&self) -> Algorithm {
+        self.keys.read().unwrap().active().algorithm()
+    }
+
+    /// Makes `key` active. Returns its kid or `None` if it's already known.
+    pub fn rotate_key(&self, key: SigningKey) -> Option<String> {
+        let kid = key.kid().map(str::to_string);
+        let retain_for = self.token_settings.ttl + self.token_settings.clock_skew;
+        if !self.keys.write().unwrap().rotate(key, retain_for) {
+            return None;
+        }
+
+        kid
+    }
+
+    /// Checks the credentials unless the login or the IP is locked out
+    /// by previous failures.
+    pub async fn authenticate(
         &self,
         login: &str,
         password: &str,
         nonce: &str,
-        ip: Ipv4Addr,
-    ) -> Option<String> {
-        // self.check_user_baned(login)?;
-        let (login, nonce, country) = {
+        ip: IpAddr,
+    ) -> Result<Tokens, AuthError> {
+        let now = unix_now();
+        let attempt = match self.auth_throttle.reserve(login, ip, now) {
+            Ok(attempt) => attempt,
+            Err(retry_after) => return Err(AuthError::Throttled { retry_after }),
+        };
+
+        if let Some(tokens) = self.issue_tokens(login, password, nonce, ip).await {
+            self.auth_throttle.succeed(login, attempt);
+            return Ok(tokens);
+        }
+
+        if let Some(client) = self.auth_throttle.fail(attempt, now) {
+            let ban = BanInfo {
+                expires_at: Some(now + self.auth_throttle.ip_ban_secs()),
+                reason: Some("too many failed logins".into()),
+                admin: None,
+            };
+            if self.ban_subnet(client.addr(), client.prefix_len(), ban) {
+                eprintln!("banned {client} after failed logins");
+            }
+        }
+
+        Err(AuthError::Invalid)
+    }
+
+    async fn issue_tokens(
+        &self,
+        login: &str,
+        password: &str,
+        nonce: &str,
+        ip: IpAddr,
+    ) -> Option<Tokens> {
+        // hashing is slow, so keep it off the worker and out of the shard lock
+        let stored = self.users.get(login).map(|user| user.password.clone());
+        // unknown logins take as long as known ones
+        let against = stored.clone().unwrap_or_else(|| dummy_hash().into());
+        let password = SmolStr::from(password);
+        let check = blocking::run(move || match verify_password(&against, &password) {
+            PasswordCheck::ValidLegacy => Ok(Some(hash_password(&password))),
+            PasswordCheck::Valid => Ok(None),
+            PasswordCheck::Invalid => Err(()),
+        })
+        .await;
+        let stored = stored?;
+        let Ok(Ok(upgraded)) = check else {
+            return None;
+        };
+
+        let (login, nonce, generation) = {
             let mut user = self.users.get_mut(login)?;
 
-            if user.password != password {
+            if user.is_banned() {
                 return None;
             }
 
-            if user.is_banned {
-                return None;
+            // password could be changed while we were verifying it
+            if let Some(upgraded) = upgraded {
+                if user.password == stored {
+                    user.password = upgraded;
+                    self.log(Mutation::put_user(&user));
+                }
             }
 
-            user.nonce = nonce.into();
+            self.is_country_ip(user.country.clone(), ip)?;
 
-            (user.login.clone(), user.nonce.clone(), user.country.clone())
-        };
+            // recorded only once the token is sure to be issued, the nonce
+            // evicted here may belong to a live token
+            while user.nonces.len() >= self.token_settings.nonce_policy.window() {
+                user.nonces.pop_front();
+            }
+            user.nonces.push_back(nonce.into());
 
-        self.is_country_ip(country, ip)?;
+            (
+                user.login.clone(),
+                SmolStr::from(nonce),
+                user.token_generation,
+            )
+        };
 
-        let info = Info {
-            login: login.clone(),
+        let (sid, refresh_token) = self.sessions.create(&login, &nonce);
+        let access_token = self.issue_access_token(Info {
+            login,
             nonce,
+            sid,
+            generation,
+        })?;
+
+        Some(Tokens {
+            access_token,
+            refresh_token,
+        })
+    }
+
+    /// Exchanges a refresh token for a new pair of access and refresh tokens.
+    pub fn refresh(&self, refresh_token: &str, ip: IpAddr) -> Result<Tokens, RefreshError> {
+        // a refused refresh must not consume the token
+        let owner = self.sessions.owner(refresh_token)?;
+
+        let (generation, country) = match self.users.get(&owner.login) {
+            Some(user)
+                if !user.is_banned()
+                    && self
+                        .token_settings
+                        .nonce_policy
+                        .is_valid(&user.nonces, &owner.nonce) =>
+            {
+                (user.token_generation, user.country.clone())
+            }
+            _ => {
+                // no token of the session would be accepted anymore
+                self.sessions.revoke(&owner.sid);
+                return Err(RefreshError::Invalid);
+            }
         };
 
-        let mut cus = jwt_simple::claims::Claims::with_custom_claims(info, Duration::from_days(1));
-        cus.issued_at = None;
-        cus.invalid_before = None;
-        cus.expires_at = None;
+        self.is_country_ip(country, ip)
+            .ok_or(RefreshError::Invalid)?;
+
+        let refreshed = self.sessions.rotate(refresh_token)?;
+        let access_token = self
+            .issue_access_token(Info {
+                login: refreshed.login,
+                // keep the nonce of the login which started the session
+                nonce: refreshed.nonce,
+                sid: refreshed.sid,
+                generation,
+            })
+            .ok_or(RefreshError::Invalid)?;
+
+        Ok(Tokens {
+            access_token,
+            refresh_token: refreshed.refresh_token,
+        })
+    }
+
+    pub fn access_token_ttl(&self) -> Duration {
+        self.token_settings.ttl
+    }
 
-        let token = self.key.authenticate(cus).ok()?;
+    fn issue_access_token(&self, info: Info) -> Option<String> {
+        let claims = jwt_simple::claims::Claims::with_custom_claims(info, self.token_settings.ttl);
 
-        Some(token)
+        self.keys.read().unwrap().active().sign(claims).ok()
     }
 
-    fn is_country_ip(&self, country: SmolStr, ip: Ipv4Addr) -> Option<()> {
+    fn is_country_ip(&self, country: SmolStr, ip: IpAddr) -> Option<()> {
         let country = self.country_prefixes.get(&country)?;
-        let subnet = Ipv4Net::new(ip, 32).unwrap();
-        if !country.contains(&subnet) {
+        let found = match ip {
+            IpAddr::V4(ip) => country.v4.contains(&ip),
+            IpAddr::V6(ip) => country.v6.contains(&ip),
+        };
+        if !found {
             return None;
         }
 
         Some(())
     }
 
-    pub fn create_user(&self, login: &str, password: &str, name: &str, phone: &str, country: &str) {
+    pub async fn create_user(
+        &self,
+        login: &str,
+        password: &str,
+        name: &str,
+        phone: &str,
+        country: &str,
+    ) -> bool {
+        let password = hash_off_worker(password).await;
         let user = User {
             login: login.into(),
-            password: password.into(),
+            password,
             name: name.into(),
             phone: phone.into(),
             country: country.into(),
             is_admin: false,
-            is_banned: false,
-            nonce: "".into(),
+            ban: None,
+            nonces: VecDeque::new(),
+            token_generation: 0,
         };
 
-        self.users.insert(user.login.clone(), user);
+        // the login could be taken while the password was hashed
+        let Entry::Vacant(entry) = self.users.entry(user.login.clone()) else {
+            return false;
+        };
+        let user = entry.insert(user);
+        self.index_ban(&user.login, None);
+        self.log(Mutation::put_user(&user));
+
+        true
     }
 
     pub fn is_user_exists(&self, login: &str) -> bool {
         self.users.contains_key(login)
     }
 
-    pub fn is_prop_admin_cred(&self, login: &str, ip: Ipv4Addr) -> bool {
+    pub fn is_prop_admin_cred(&self, login: &str, ip: IpAddr) -> bool {
         let country = match self.users.get(login) {
             Some(usr) => {
                 if !usr.is_admin {
@@ -163,33 +502,32 @@ impl State {
         self.is_country_ip(country, ip).is_some()
     }
 
-    pub fn get_user(&self, login: SmolStr, ip: Ipv4Addr) -> Option<String> {
+    pub fn get_user(&self, login: SmolStr, ip: IpAddr) -> Result<String, AccessError> {
         // self.check_user_baned(login.as_str())?;
         let user = {
-            let Some(rec) = self.users.get(&login) else {
-                return None;
-            };
+            let rec = self.users.get(&login).ok_or(AccessError::UnknownUser)?;
 
-            if rec.value().is_banned {
-                return None;
+            if rec.value().is_banned() {
+                return Err(AccessError::Banned);
             }
 
             rec.value().clone()
         };
 
-        self.is_country_ip(user.country.clone(), ip)?;
+        self.is_country_ip(user.country.clone(), ip)
+            .ok_or(AccessError::WrongCountry)?;
 
-        Some(serde_json::to_string(&user).unwrap())
+        Ok(serde_json::to_string(&user).unwrap())
     }
 
-    pub fn is_proper_country(&self, login: SmolStr, ip: Ipv4Addr) -> Option<()> {
+    pub fn is_proper_country(&self, login: SmolStr, ip: IpAddr) -> Option<()> {
         let country = self.users.get(&login)?.value().country.clone();
         self.is_country_ip(country, ip);
 
         Some(())
     }
 
-    pub fn edit_user(
+    pub async fn edit_user(
         &self,
         login: SmolStr,
         name: Option<&str>,
@@ -198,9 +536,14 @@ impl State {
         is_admin: Option<bool>,
         country: Option<SmolStr>,
     ) -> Option<()> {
-        let mut usr = self.users.get_mut(&login).unwrap();
+        let password = match password {
+            Some(password) => Some(hash_off_worker(&password).await),
+            None => None,
+        };
+        // the user could be deleted after the token was checked
+        let mut usr = self.users.get_mut(&login)?;
 
-        if usr.is_banned {
+        if usr.is_banned() {
             return None;
         }
 
@@ -226,116 +569,540 @@ impl State {
             usr.phone = phone.into();
         }
 
+        self.log(Mutation::put_user(&usr));
+
         Some(())
     }
 
-    pub fn get_user_login(&self, jwt: &str) -> Option<SmolStr> {
-        let claims = self.key.verify_token::<Info>(jwt, None).ok()?;
-        if !self.users.contains_key(&claims.custom.login) {
-            return None;
+    pub fn get_user_login(&self, jwt: &str) -> Result<Identity, TokenError> {
+        let metadata = Token::decode_metadata(jwt).map_err(|_| TokenError::Invalid)?;
+        let options = VerificationOptions {
+            time_tolerance: Some(self.token_settings.clock_skew),
+            ..Default::default()
+        };
+
+        let claims = {
+            let keys = self.keys.read().unwrap();
+            let key = keys.find(metadata.key_id()).ok_or(TokenError::Invalid)?;
+            key.verify::<Info>(jwt, Some(options)).map_err(|e| {
+                match e.downcast_ref::<JWTError>() {
+                    Some(JWTError::TokenHasExpired) => TokenError::Expired,
+                    _ => TokenError::Invalid,
+                }
+            })?
+        };
+
+        // tokens issued before expiry was enforced never expire, don't accept them
+        if claims.expires_at.is_none() {
+            return Err(TokenError::Invalid);
+        }
+
+        if !self.sessions.is_active(&claims.custom.sid) {
+            return Err(TokenError::Invalid);
+        }
+
+        match self.users.get(&claims.custom.login) {
+            Some(user)
+                if user.token_generation == claims.custom.generation
+                    && self
+                        .token_settings
+                        .nonce_policy
+                        .is_valid(&user.nonces, claims.nonce.as_deref().unwrap_or_default()) => {}
+            _ => return Err(TokenError::Invalid),
         }
 
-        Some(claims.custom.login)
+        Ok(Identity {
+            login: claims.custom.login,
+            sid: claims.custom.sid,
+        })
     }
 
-    pub fn ban_user(&self, login: &str) -> Option<bool> {
+    /// Ends the session, its access and refresh tokens are no longer accepted.
+    pub fn logout(&self, sid: &str) -> bool {
+        self.sessions.revoke(sid)
+    }
+
+    /// Invalidates every token issued to `login` so far.
+    pub fn revoke_user_tokens(&self, login: &str) -> Option<()> {
+        self.users.get_mut(login)?.token_generation += 1;
+        self.sessions.revoke_login(login);
+        Some(())
+    }
+
+    /// Restores a user record, used by journal replay.
+    pub fn put_user(&self, user: User) {
+        if let Some(ban) = &user.ban {
+            self.schedule_expiry(BanTarget::User(user.login.clone()), ban);
+        }
+        let login = user.login.clone();
+        let ban = user.ban.clone();
+        // the index is updated under the lock of the new record
+        let rec = self.users.entry(login.clone()).insert(user);
+        self.index_ban(&login, ban);
+        drop(rec);
+    }
+
+    pub fn ban_user(&self, login: &str, ban: BanInfo) -> Option<bool> {
         let mut rec = self.users.get_mut(login)?;
-        if rec.is_banned {
+        if rec.is_banned() {
             return Some(false);
         }
-        rec.is_banned = true;
+        self.schedule_expiry(BanTarget::User(rec.login.clone()), &ban);
+        self.index_ban(&rec.login, Some(ban.clone()));
+        rec.ban = Some(ban);
+        rec.token_generation += 1;
+        self.log(Mutation::put_user(&rec));
+        drop(rec);
+
+        self.sessions.revoke_login(login);
         Some(true)
     }
 
     pub fn unban_user(&self, login: &str) -> Option<bool> {
         let mut rec = self.users.get_mut(login)?;
-        if !rec.is_banned {
+        if !rec.is_banned() {
             return Some(false);
         }
-        rec.is_banned = false;
+        rec.ban = None;
+        self.index_ban(&rec.login, None);
+        self.log(Mutation::put_user(&rec));
         Some(true)
     }
 
-    pub fn is_ip_banned(&self, ip: Ipv4Addr) -> bool {
-        if let Some(subnets) = self.first_byte_banned_subnets.get(&ip.octets()[0]) {
-            for subnet in subnets.value() {
-                if subnet.contains(&ip) {
-                    return true;
-                }
-            }
-        }
+    // must be called under the lock of the user record
+    fn index_ban(&self, login: &SmolStr, ban: Option<BanInfo>) {
+        let mut banned = self.banned_users.lock().unwrap();
+        match ban {
+            Some(ban) => banned.insert(login.clone(), ban),
+            None => banned.remove(login),
+        };
+    }
 
-        // for subnet in self.root_banned_subnets.iter() {
-        //     if subnet.contains(&ip) {
-        //         return true;
-        //     }
-        // }
+    /// Banned users in ascending order of logins.
+    pub fn banned_users(&self) -> Vec<BannedUser> {
+        self.banned_users
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(login, ban)| BannedUser {
+                login: login.clone(),
+                ban: ban.clone(),
+            })
+            .collect()
+    }
 
-        false
+    pub fn banned_subnet_rules(&self) -> Vec<BannedSubnet> {
+        let bans = self.subnet_bans.lock().unwrap();
+        self.banned_subnets
+            .rules()
+            .into_iter()
+            .map(|subnet| BannedSubnet {
+                subnet,
+                ban: bans.get(&subnet).cloned().unwrap_or_default(),
+            })
+            .collect()
     }
 
-    pub fn ban_subnet(&self, network: Ipv4Addr, mask: u8) -> bool {
-        let fb = network.octets()[0];
-        let subnet = Ipv4Net::new(network, mask).unwrap();
+    pub fn is_ip_banned(&self, ip: IpAddr) -> bool {
+        self.banned_subnets.banned_by(ip).is_some()
+    }
 
-        // if mask < 8 {
-        //     if self.root_banned_subnets.contains(&subnet) {
-        //         return false;
-        //     }
+    /// The most specific ban rule covering `ip`.
+    pub fn banned_by(&self, ip: IpAddr) -> Option<BannedSubnet> {
+        let bans = self.subnet_bans.lock().unwrap();
+        let subnet = self.banned_subnets.banned_by(ip)?;
+        Some(BannedSubnet {
+            subnet,
+            ban: bans.get(&subnet).cloned().unwrap_or_default(),
+        })
+    }
 
-        //     self.root_banned_subnets.insert(subnet);
-        //     true
-        // } else {
-            let mut fb = self.first_byte_banned_subnets.entry(fb).or_default();
-            for &stored_subnet in fb.value().iter() {
-                if stored_subnet == subnet {
-                    return false;
+    pub fn ban_subnet(&self, network: IpAddr, mask: u8, ban: BanInfo) -> bool {
+        let subnet = blacklist::canonical(IpNet::new(network, mask).unwrap());
+        let mut bans = self.subnet_bans.lock().unwrap();
+        if !self.banned_subnets.ban(subnet) {
+            return false;
+        }
+
+        self.schedule_expiry(BanTarget::Subnet(subnet), &ban);
+        self.log(Mutation::BanSubnet {
+            network,
+            mask,
+            ban: ban.clone(),
+        });
+        bans.insert(subnet, ban);
+        true
+    }
+
+    pub fn unban_subnet(&self, network: IpAddr, mask: u8) -> bool {
+        let subnet = blacklist::canonical(IpNet::new(network, mask).unwrap());
+        let mut bans = self.subnet_bans.lock().unwrap();
+        if !self.banned_subnets.unban(subnet) {
+            return false;
+        }
+
+        bans.remove(&subnet);
+        self.log(Mutation::UnbanSubnet { network, mask });
+        true
+    }
+
+    fn schedule_expiry(&self, target: BanTarget, ban: &BanInfo) {
+        if let Some(expires_at) = ban.expires_at {
+            self.ban_expiry.lock().unwrap().insert(expires_at, target);
+        }
+    }
+
+    /// Lifts bans expired by `now`, returns how many were lifted.
+    pub fn expire_bans(&self, now: u64) -> usize {
+        let expired = self.ban_expiry.lock().unwrap().advance(now);
+
+        let mut lifted = 0;
+        for (expires_at, target) in expired {
+            // the ban could be lifted or issued again since the timer was set
+            match target {
+                BanTarget::User(login) => {
+                    let Some(mut rec) = self.users.get_mut(&login) else {
+                        continue;
+                    };
+                    if rec.ban.as_ref().and_then(|ban| ban.expires_at) != Some(expires_at) {
+                        continue;
+                    }
+                    rec.ban = None;
+                    self.index_ban(&rec.login, None);
+                    self.log(Mutation::put_user(&rec));
+                }
+                BanTarget::Subnet(subnet) => {
+                    let mut bans = self.subnet_bans.lock().unwrap();
+                    if bans.get(&subnet).and_then(|ban| ban.expires_at) != Some(expires_at) {
+                        continue;
+                    }
+                    bans.remove(&subnet);
+                    self.banned_subnets.unban(subnet);
+                    self.log(Mutation::UnbanSubnet {
+                        network: subnet.addr(),
+                        mask: subnet.prefix_len(),
+                    });
                 }
             }
-            fb.push(subnet);
-            true
-        // }
+            lifted += 1;
+        }
 
-        // if self.first_byte_banned_subnets.contains(&subnet) {
-        //     return false;
-        // }
-        // self.first_byte_banned_subnets.insert(subnet);
+        lifted
+    }
+}
 
-        // self.banned.contains(subnet)
+/// Settings of a test state, defaults unless overridden.
+#[cfg(test)]
+pub struct TestOptions {
+    pub country_prefixes: HashMap<SmolStr, CountryPrefixes>,
+    pub nonce_policy: NoncePolicy,
+    pub auth: AuthConfig,
+}
+
+#[cfg(test)]
+impl Default for TestOptions {
+    fn default() -> Self {
+        TestOptions {
+            country_prefixes: HashMap::new(),
+            nonce_policy: NoncePolicy::Any,
+            auth: AuthConfig::default(),
+        }
     }
+}
 
-    pub fn unban_subnet(&self, network: Ipv4Addr, mask: u8) -> bool {
-        let fb = network.octets()[0];
-        let subnet = Ipv4Net::new(network, mask).unwrap();
+#[cfg(test)]
+impl State {
+    /// Empty state signing with a fresh HS256 key.
+    pub fn for_test(options: TestOptions) -> State {
+        let settings = TokenSettings {
+            ttl: Duration::from_mins(1),
+            refresh_ttl: Duration::from_hours(1),
+            clock_skew: Duration::from_secs(0),
+            nonce_policy: options.nonce_policy,
+        };
 
-        if mask < 8 {
-            if !self.root_banned_subnets.contains(&subnet) {
-                return false;
-            }
+        State::new(
+            DashMap::new(),
+            options.country_prefixes,
+            SigningKey::generate(Algorithm::HS256).unwrap(),
+            settings,
+            options.auth,
+            &RateLimitConfig::default(),
+        )
+    }
+}
 
-            self.root_banned_subnets.remove(&subnet);
-            true
-        } else {
-            let retain: bool = {
-                let Some(mut subnets) = self.first_byte_banned_subnets.get_mut(&fb) else {
-                    return false;
-                };
+#[cfg(test)]
+mod test {
+    use std::collections::{HashMap, VecDeque};
+
+    use smol_str::SmolStr;
+
+    use crate::{
+        ban::{unix_now, BanInfo},
+        config::AuthConfig,
+        session::RefreshError,
+        state::{AccessError, AuthError, CountryPrefixes, NoncePolicy, State, TestOptions},
+    };
+
+    #[test]
+    fn test_nonce_policy() {
+        assert_eq!(NoncePolicy::Any, "any".parse().unwrap());
+        assert_eq!(NoncePolicy::LastN(1), "latest".parse().unwrap());
+        assert_eq!(NoncePolicy::LastN(3), "last:3".parse().unwrap());
+        assert!("last:0".parse::<NoncePolicy>().is_err());
+        assert!("last".parse::<NoncePolicy>().is_err());
+
+        let nonces: VecDeque<SmolStr> = ["a", "b", "c"].into_iter().map(SmolStr::from).collect();
+        assert!(NoncePolicy::Any.is_valid(&nonces, "z"));
+        assert!(NoncePolicy::LastN(1).is_valid(&nonces, "c"));
+        assert!(!NoncePolicy::LastN(1).is_valid(&nonces, "b"));
+        assert!(NoncePolicy::LastN(2).is_valid(&nonces, "b"));
+        assert!(!NoncePolicy::LastN(2).is_valid(&nonces, "a"));
+    }
 
-                match subnets.iter().position(|&snet| snet == subnet) {
-                    Some(idx) => {
-                        subnets.remove(idx);
-                        true
-                    }
+    #[monoio::test(timer_enabled = true)]
+    async fn test_ipv6() {
+        let mut russia = CountryPrefixes::default();
+        russia.v4.add("1.2.3.0/24".parse().unwrap());
+        russia.v6.add("2a00::/16".parse().unwrap());
+        let prefixes = HashMap::from([(SmolStr::from("Russia"), russia)]);
+
+        let state = State::for_test(TestOptions {
+            country_prefixes: prefixes,
+            ..TestOptions::default()
+        });
+        state
+            .create_user("abcde", "secret", "name", "phone", "Russia")
+            .await;
+
+        let user = |ip: &str| state.get_user("abcde".into(), ip.parse().unwrap());
+        assert!(user("1.2.3.4").is_ok());
+        assert!(user("2a00:1::1").is_ok());
+        assert_eq!(Err(AccessError::WrongCountry), user("2a01::1"));
+
+        let network = "2a00:1::".parse().unwrap();
+        assert!(state.ban_subnet(network, 32, BanInfo::default()));
+        assert!(!state.ban_subnet(network, 32, BanInfo::default()));
+        assert!(state.is_ip_banned("2a00:1:ffff::1".parse().unwrap()));
+        assert!(!state.is_ip_banned("2a00:2::1".parse().unwrap()));
+        assert!(!state.is_ip_banned("1.2.3.4".parse().unwrap()));
+
+        assert!(state.unban_subnet(network, 32));
+        assert!(!state.unban_subnet(network, 32));
+        assert!(!state.is_ip_banned("2a00:1:ffff::1".parse().unwrap()));
+    }
 
-                    None => return false,
-                }
-            };
+    #[monoio::test(timer_enabled = true)]
+    async fn test_token_nonce() {
+        let mut russia = CountryPrefixes::default();
+        russia.v4.add("1.2.3.0/24".parse().unwrap());
+        let prefixes = HashMap::from([(SmolStr::from("Russia"), russia)]);
+
+        let state = State::for_test(TestOptions {
+            country_prefixes: prefixes,
+            nonce_policy: NoncePolicy::LastN(1),
+            ..TestOptions::default()
+        });
+        state
+            .create_user("abcde", "secret", "name", "phone", "Russia")
+            .await;
+
+        let ip = "1.2.3.4".parse().unwrap();
+        let first = state
+            .authenticate("abcde", "secret", "a", ip)
+            .await
+            .unwrap();
+        assert_eq!(
+            "abcde",
+            state.get_user_login(&first.access_token).unwrap().login
+        );
+
+        // the nonce travels in the standard claim, the latest login wins
+        let second = state
+            .authenticate("abcde", "secret", "b", ip)
+            .await
+            .unwrap();
+        assert!(state.get_user_login(&first.access_token).is_err());
+        assert!(state.get_user_login(&second.access_token).is_ok());
+    }
 
-            if retain {
-                self.first_byte_banned_subnets.retain(|_k, v| !v.is_empty());
-            }
+    #[monoio::test(timer_enabled = true)]
+    async fn test_refresh() {
+        let mut russia = CountryPrefixes::default();
+        russia.v4.add("1.2.3.0/24".parse().unwrap());
+        let prefixes = HashMap::from([(SmolStr::from("Russia"), russia)]);
+
+        let state = State::for_test(TestOptions {
+            country_prefixes: prefixes,
+            nonce_policy: NoncePolicy::LastN(1),
+            ..TestOptions::default()
+        });
+        state
+            .create_user("abcde", "secret", "name", "phone", "Russia")
+            .await;
+
+        let ip = "1.2.3.4".parse().unwrap();
+        let first = state
+            .authenticate("abcde", "secret", "a", ip)
+            .await
+            .unwrap();
+
+        // a refused refresh leaves the token usable
+        let abroad = "5.6.7.8".parse().unwrap();
+        assert_eq!(
+            Err(RefreshError::Invalid),
+            state.refresh(&first.refresh_token, abroad).map(|_| ())
+        );
+        let refreshed = state.refresh(&first.refresh_token, ip).unwrap();
+        assert!(state.get_user_login(&refreshed.access_token).is_ok());
+
+        // the nonce of the session is superseded, its tokens are dead
+        let second = state
+            .authenticate("abcde", "secret", "b", ip)
+            .await
+            .unwrap();
+        assert_eq!(
+            Err(RefreshError::Invalid),
+            state.refresh(&refreshed.refresh_token, ip).map(|_| ())
+        );
+        assert_eq!(
+            Err(RefreshError::Invalid),
+            state.refresh(&refreshed.refresh_token, ip).map(|_| ())
+        );
+
+        let refreshed = state.refresh(&second.refresh_token, ip).unwrap();
+        assert!(state.get_user_login(&refreshed.access_token).is_ok());
+    }
+
+    #[monoio::test(timer_enabled = true)]
+    async fn test_refused_login_keeps_nonce() {
+        let mut russia = CountryPrefixes::default();
+        russia.v4.add("1.2.3.0/24".parse().unwrap());
+        let prefixes = HashMap::from([(SmolStr::from("Russia"), russia)]);
+
+        let state = State::for_test(TestOptions {
+            country_prefixes: prefixes,
+            nonce_policy: NoncePolicy::LastN(1),
+            ..TestOptions::default()
+        });
+        state
+            .create_user("abcde", "secret", "name", "phone", "Russia")
+            .await;
+
+        let ip = "1.2.3.4".parse().unwrap();
+        let tokens = state
+            .authenticate("abcde", "secret", "a", ip)
+            .await
+            .unwrap();
 
-            true
+        // the right password from the wrong country issues nothing
+        let abroad = "5.6.7.8".parse().unwrap();
+        assert_eq!(
+            Err(AuthError::Invalid),
+            state
+                .authenticate("abcde", "secret", "b", abroad)
+                .await
+                .map(|_| ())
+        );
+        assert!(state.get_user_login(&tokens.access_token).is_ok());
+    }
+
+    #[monoio::test(timer_enabled = true)]
+    async fn test_ban_expiry() {
+        let state = State::for_test(TestOptions::default());
+        state
+            .create_user("abcde", "secret", "name", "phone", "Russia")
+            .await;
+        state
+            .create_user("fghij", "secret", "name", "phone", "Russia")
+            .await;
+
+        let now = unix_now();
+        let expiring = |secs| BanInfo {
+            expires_at: Some(now + secs),
+            reason: Some("spam".into()),
+            admin: Some("root".into()),
+        };
+        let network = "10.0.0.0".parse().unwrap();
+        assert_eq!(Some(true), state.ban_user("abcde", expiring(10)));
+        assert_eq!(Some(true), state.ban_user("fghij", BanInfo::default()));
+        assert!(state.ban_subnet(network, 8, expiring(10)));
+        let logins = |state: &State| -> Vec<SmolStr> {
+            state
+                .banned_users()
+                .into_iter()
+                .map(|user| user.login)
+                .collect()
+        };
+        assert_eq!(vec!["abcde", "fghij"], logins(&state));
+
+        // banned again for longer, the first timer is stale
+        assert!(state.unban_subnet(network, 8));
+        assert!(state.ban_subnet(network, 8, expiring(20)));
+
+        let ip = "10.1.1.1".parse().unwrap();
+        assert_eq!(0, state.expire_bans(now + 9));
+        assert_eq!(1, state.expire_bans(now + 10));
+        assert!(!state.users.get("abcde").unwrap().is_banned());
+        assert!(state.users.get("fghij").unwrap().is_banned());
+        assert_eq!(Some(expiring(20)), state.banned_by(ip).map(|rule| rule.ban));
+
+        assert_eq!(1, state.expire_bans(now + 20));
+        assert!(!state.is_ip_banned(ip));
+        assert_eq!(vec!["fghij"], logins(&state));
+        assert!(state.banned_subnet_rules().is_empty());
+    }
+
+    #[monoio::test(timer_enabled = true)]
+    async fn test_brute_force() {
+        let mut russia = CountryPrefixes::default();
+        russia.v4.add("1.2.3.0/24".parse().unwrap());
+        let prefixes = HashMap::from([(SmolStr::from("Russia"), russia)]);
+
+        let auth = AuthConfig {
+            login_free_attempts: 1,
+            ip_free_attempts: 100,
+            lockout_secs: 60,
+            ip_ban_failures: 5,
+            ..AuthConfig::default()
+        };
+        let state = State::for_test(TestOptions {
+            country_prefixes: prefixes,
+            auth,
+            ..TestOptions::default()
+        });
+        state
+            .create_user("abcde", "secret", "name", "phone", "Russia")
+            .await;
+
+        // known and unknown logins are locked out the same way
+        let ip = "1.2.3.4".parse().unwrap();
+        for login in ["abcde", "nobody"] {
+            let auth = |password| state.authenticate(login, password, "n", ip);
+            assert_eq!(Err(AuthError::Invalid), auth("wrong").await.map(|_| ()));
+            assert_eq!(Err(AuthError::Invalid), auth("wrong").await.map(|_| ()));
+            // even with the right password
+            assert!(matches!(
+                auth("secret").await.map(|_| ()),
+                Err(AuthError::Throttled {
+                    retry_after: 1..=60
+                })
+            ));
         }
+
+        // the fifth failure from the IP bans it, throttled attempts don't count
+        assert!(!state.is_ip_banned(ip));
+        assert!(state.authenticate("fghij", "x", "n", ip).await.is_err());
+        let rule = state.banned_by(ip).unwrap();
+        assert_eq!(32, rule.subnet.prefix_len());
+        assert!(rule.ban.expires_at.is_some());
+
+        let other = "1.2.3.5".parse().unwrap();
+        assert!(state.authenticate("fghij", "x", "n", other).await.is_err());
+        assert!(state
+            .authenticate("fghij", "secret", "n", other)
+            .await
+            .is_err());
     }
 }
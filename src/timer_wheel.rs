@@ -0,0 +1,88 @@
+// one slot per second, deadlines further than a full turn wait for their round
+const SLOTS: u64 = 512;
+
+/// Hashed timer wheel with one second resolution.
+///
+/// Insertion is O(1), advancing by a second looks at one slot only.
+/// Deadlines are unix timestamps in seconds.
+pub struct TimerWheel<T> {
+    slots: Vec<Vec<(u64, T)>>,
+    // the first second not processed yet
+    current: u64,
+}
+
+impl<T> TimerWheel<T> {
+    pub fn new(now: u64) -> TimerWheel<T> {
+        TimerWheel {
+            slots: (0..SLOTS).map(|_| Vec::new()).collect(),
+            current: now,
+        }
+    }
+
+    /// A deadline in the past fires on the next `advance`.
+    pub fn insert(&mut self, deadline: u64, item: T) {
+        let at = deadline.max(self.current);
+        self.slots[(at % SLOTS) as usize].push((deadline, item));
+    }
+
+    /// Takes out items with deadline not later than `now`.
+    pub fn advance(&mut self, now: u64) -> Vec<(u64, T)> {
+        let mut expired = Vec::new();
+        // after a long pause every slot is visited once
+        let steps = (now + 1).saturating_sub(self.current).min(SLOTS);
+        for step in 0..steps {
+            let slot = &mut self.slots[((self.current + step) % SLOTS) as usize];
+            let mut idx = 0;
+            while idx < slot.len() {
+                if slot[idx].0 <= now {
+                    expired.push(slot.swap_remove(idx));
+                } else {
+                    idx += 1;
+                }
+            }
+        }
+        self.current = self.current.max(now + 1);
+
+        expired
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::timer_wheel::{TimerWheel, SLOTS};
+
+    fn items(mut expired: Vec<(u64, &'static str)>) -> Vec<&'static str> {
+        expired.sort_unstable();
+        expired.into_iter().map(|(_, item)| item).collect()
+    }
+
+    #[test]
+    fn test_advance() {
+        let mut wheel = TimerWheel::new(100);
+        wheel.insert(101, "a");
+        wheel.insert(105, "b");
+        wheel.insert(105, "c");
+        // already due
+        wheel.insert(50, "d");
+
+        assert_eq!(vec!["d"], items(wheel.advance(100)));
+        assert!(wheel.advance(100).is_empty());
+        assert_eq!(vec!["a"], items(wheel.advance(104)));
+        assert_eq!(vec!["b", "c"], items(wheel.advance(110)));
+        assert!(wheel.advance(200).is_empty());
+    }
+
+    #[test]
+    fn test_rounds() {
+        let mut wheel = TimerWheel::new(0);
+        // the same slot, different turns of the wheel
+        wheel.insert(10, "a");
+        wheel.insert(10 + SLOTS, "b");
+        wheel.insert(10 + 3 * SLOTS, "c");
+
+        assert_eq!(vec!["a"], items(wheel.advance(SLOTS)));
+        assert_eq!(vec!["b"], items(wheel.advance(SLOTS + 10)));
+        // a pause longer than a turn still finds everything due
+        assert_eq!(vec!["c"], items(wheel.advance(10 * SLOTS)));
+    }
+}
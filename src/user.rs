@@ -0,0 +1,94 @@
+use std::sync::OnceLock;
+
+use argon2::{
+    password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
+use smol_str::SmolStr;
+use subtle::ConstantTimeEq;
+
+use crate::blocking;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum PasswordCheck {
+    Valid,
+    // password is valid, but stored as plaintext and must be rehashed
+    ValidLegacy,
+    Invalid,
+}
+
+// a plaintext password may look like a hash, only a well-formed argon2 PHC
+// string is taken for one
+fn parse_hash(stored: &str) -> Option<PasswordHash<'_>> {
+    let hash = PasswordHash::new(stored).ok()?;
+    argon2::Algorithm::try_from(hash.algorithm).ok()?;
+    Some(hash)
+}
+
+pub fn hash_password(password: &str) -> SmolStr {
+    let salt = SaltString::generate(&mut OsRng);
+    let hash = Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .expect("argon2 with default params never fails");
+
+    hash.to_string().into()
+}
+
+/// Hashes on the blocking pool, argon2 would stall every connection of the worker.
+pub async fn hash_off_worker(password: &str) -> SmolStr {
+    let password = SmolStr::from(password);
+    blocking::run(move || hash_password(&password))
+        .await
+        .expect("argon2 with default params never fails")
+}
+
+/// Hash to verify passwords of unknown logins against, so they take as long
+/// as the known ones.
+pub fn dummy_hash() -> &'static str {
+    static DUMMY: OnceLock<SmolStr> = OnceLock::new();
+    DUMMY.get_or_init(|| hash_password("dummy password"))
+}
+
+pub fn verify_password(stored: &str, password: &str) -> PasswordCheck {
+    let Some(hash) = parse_hash(stored) else {
+        return match bool::from(stored.as_bytes().ct_eq(password.as_bytes())) {
+            true => PasswordCheck::ValidLegacy,
+            false => PasswordCheck::Invalid,
+        };
+    };
+
+    // argon2 compares the digests in constant time
+    match Argon2::default().verify_password(password.as_bytes(), &hash) {
+        Ok(()) => PasswordCheck::Valid,
+        Err(_) => PasswordCheck::Invalid,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::user::{hash_password, parse_hash, verify_password, PasswordCheck};
+
+    #[test]
+    fn test_password() {
+        let hash = hash_password("secret");
+        assert!(parse_hash(&hash).is_some());
+        assert_ne!(hash_password("secret"), hash);
+
+        assert_eq!(PasswordCheck::Valid, verify_password(&hash, "secret"));
+        assert_eq!(PasswordCheck::Invalid, verify_password(&hash, "Secret"));
+
+        assert_eq!(
+            PasswordCheck::ValidLegacy,
+            verify_password("secret", "secret")
+        );
+        assert_eq!(PasswordCheck::Invalid, verify_password("secret", "secre"));
+        assert_eq!(PasswordCheck::Invalid, verify_password("", "secret"));
+
+        // plaintext which only starts like a hash
+        assert!(parse_hash("$argon2 is my password").is_none());
+        assert_eq!(
+            PasswordCheck::ValidLegacy,
+            verify_password("$argon2 is my password", "$argon2 is my password")
+        );
+    }
+}